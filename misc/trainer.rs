@@ -1,503 +1,912 @@
-use std::{collections::{HashSet, HashMap, VecDeque, hash_map::Entry}, path::Path, ops::ControlFlow};
-use rand::{Rng, distributions::WeightedIndex, prelude::SliceRandom};
-use serde::{Serialize, Deserialize};
+use std::{collections::{HashMap, hash_map::Entry}, convert::TryInto, fmt, path::{Path, PathBuf}, ops::ControlFlow, str::FromStr};
+use clap::{Parser, Subcommand};
+use rand::{Rng, SeedableRng, distributions::WeightedIndex, prelude::{SliceRandom, StdRng}};
+use serde::{Serialize, Serializer, Deserialize};
+use tictac2player::{
+    Board, CellValue, Geometry, InfosetKey, MAX_CELLS, Move, MoveSet, Outcome, Strategy, StrategyError,
+    exploitability::exploitability_on, policy::{Policy, uniform_over},
+    strategy::{StrategyHeader, codec::{self, TrainedInfoset, VAL_EXPANSION}},
+};
+
+// == command line ==
+#[derive(Parser)]
+#[command(about = "Trains the advice strategy with CFR, and exports it for the game")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Train until the checkpoint has done `--iterations` iterations, starting one if there isn't one yet
+    Train {
+        /// The checkpoint to resume from and save to
+        #[arg(long, default_value = "cfr.dat")]
+        cfr: PathBuf,
+        /// Total iterations to train to. I've been using 40000 lately
+        #[arg(long, default_value_t = 40000)]
+        iterations: u64,
+        /// Iterations for contempt to drop to its floor. Defaults to the checkpoint's, or 10000 for a new one
+        #[arg(long)]
+        contempt_iterations: Option<u64>,
+        /// Contempt at the first iteration. Defaults to the checkpoint's, or 0.5 for a new one
+        #[arg(long, value_parser = parse_probability)]
+        contempt_start: Option<f32>,
+        /// Contempt never drops below this. Defaults to the checkpoint's, or 0.01 for a new one
+        #[arg(long, value_parser = parse_probability)]
+        contempt_floor: Option<f32>,
+        /// Save the checkpoint every this many iterations
+        #[arg(long, default_value_t = 1000)]
+        save_every: u64,
+        /// How regrets and averages are updated: vanilla, cfr+, linear or dcfr[:alpha,beta,gamma].
+        /// Defaults to the checkpoint's, or vanilla for a new one
+        #[arg(long)]
+        rule: Option<UpdateRule>,
+        /// Print the average strategy's exploitability every this many iterations (boards up to 3x3 only: it's a full tree walk)
+        #[arg(long)]
+        report_every: Option<u64>,
+        /// How much of the tree an iteration walks: full, external or outcome[:exploration].
+        /// Defaults to the checkpoint's, or full for a new one
+        #[arg(long)]
+        sampling: Option<Sampling>,
+        /// Seeds the sampling. Defaults to the checkpoint's, or 0 for a new one
+        #[arg(long)]
+        seed: Option<u64>,
+        /// The board for a new checkpoint, like "4x4k3" (width x height, k in a row)
+        #[arg(long)]
+        board: Option<Geometry>,
+        /// Print progress every this many iterations. Defaults to every one for full walks, every 10000 when sampling
+        #[arg(long)]
+        log_every: Option<u64>,
+        /// Split full walks between this many threads. A checkpoint trains the same way every time with the same number
+        #[arg(long, default_value_t = 1)]
+        threads: usize,
+    },
+    /// Write the checkpoint's average strategy in the format the game loads
+    Export {
+        #[arg(long, default_value = "cfr.dat")]
+        cfr: PathBuf,
+        #[arg(long, short, default_value = "strategy.dat")]
+        out: PathBuf,
+        /// The 16 probabilities strategies are rounded to, comma-separated. The first has to be 0
+        #[arg(long, value_parser = parse_val_expansion)]
+        val_expansion: Option<[f32; 16]>,
+    },
+    /// Play one game between the checkpoint's average strategies, showing every move
+    Play {
+        #[arg(long, default_value = "cfr.dat")]
+        cfr: PathBuf,
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+    /// Summarise a checkpoint, or an exported strategy with `--strategy`
+    Inspect {
+        #[arg(long, default_value = "cfr.dat")]
+        cfr: PathBuf,
+        #[arg(long)]
+        strategy: Option<PathBuf>,
+        /// Also show what the strategy does in this position, e.g. "x../.o./... x o,tie r0 k3"
+        #[arg(long)]
+        position: Option<Board>,
+    },
+}
+
+fn parse_val_expansion(text: &str) -> Result<[f32; 16], String> {
+    let values = text.split(',')
+        .map(|v| v.trim().parse::<f32>().map_err(|e| format!("{:?}: {}", v, e)))
+        .collect::<Result<Vec<f32>, String>>()?;
+    let table: [f32; 16] = values.try_into().map_err(|v: Vec<f32>| format!("need 16 values, not {}", v.len()))?;
+
+    // the game checks the table the same way when it loads the file
+    StrategyHeader::check_val_expansion(&table)
+        .map_err(|_| "the first value has to be 0, and the rest between 0 and 1 with at least one above 0".to_string())?;
+    Ok(table)
+}
+
+// contempt is the chance of playing uniformly instead, so it has to be a probability
+fn parse_probability(text: &str) -> Result<f32, String> {
+    let p = text.trim().parse::<f32>().map_err(|e| format!("{:?}: {}", text, e))?;
+    if !(0.0..=1.0).contains(&p) { return Err("has to be between 0 and 1".to_string()) }
+    Ok(p)
+}
 
 // == base game ==
-const N_MOVES: usize = 9;
-const CONTEMPT_ITERATIONS: u64 = 10000; // Iterations for contempt to drop to a very very low number
-const DESIRED_ITERATIONS: u64 = 40000; // // NOTE: I've been using 40000 lately, but I drop it to 0 to force a strategy export
-const SAVE_EVERY: u64 = 1000;
-
-#[derive(Clone, Copy, PartialEq, Eq)]
-enum CellValue { Empty, P0, P1 }
-
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
-enum Outcome { Tie, P0Win, P1Win }
-
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-struct Move(usize);
-
-#[derive(Clone, Copy)]
-struct Board {
-    cells: [CellValue; N_MOVES],
-    p0_wants: Outcome,
-    p1_wants: Outcome,
-    turn: u8,
-}
-
-#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
-struct Infoset { 
-    p0_private: u32, 
-    p1_private: u32,
-    history: u32
-}
-
-#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
-struct State(u32);
-
-impl Board {
-    fn possible_starts() -> Vec<Board> {
-        let mut result = vec![];
-        for p0 in [Outcome::Tie, Outcome::P0Win, Outcome::P1Win] {
-            for p1 in [Outcome::Tie, Outcome::P0Win, Outcome::P1Win] {
-                result.push(Board { 
-                    cells: [CellValue::Empty; N_MOVES] ,
-                    p0_wants: p0,
-                    p1_wants: p1,
-                    turn: 0,
-                });
-            }
-        };
-        result
-    }
+// the rules all come from the engine's `Board`, so the trainer can't disagree with the game about them
+
+fn possible_starts(geometry: Geometry) -> Vec<Board> {
+    let mut result = vec![];
+    for p0 in Outcome::ALL {
+        for p1 in Outcome::ALL {
+            result.push(Board::with_geometry(geometry, p0, p1));
+        }
+    };
+    result
+}
+
+// CFR
+#[derive(Clone, Debug, Serialize, Deserialize)]  // no Copy: you probably don't want to copy these as they are frequently mutated in place
+struct InfosetNode {
+    legal: [bool; MAX_CELLS],
+    regret_sum: [f32; MAX_CELLS],
+    strategy_sum: [f32; MAX_CELLS],
+}
 
-    fn next_to_move(&self) -> CellValue {
-        if self.turn % 2 == 0 { CellValue::P0 } else { CellValue::P1 }
+impl InfosetNode {
+    fn new(board: &Board) -> InfosetNode {
+        let mut legal = [false; MAX_CELLS];
+        for m in board.possible_moves() { legal[m.index()] = true; }
+        InfosetNode { legal, regret_sum: [0.0; MAX_CELLS], strategy_sum: [0.0; MAX_CELLS] }
     }
 
-    fn possible_moves(&self) -> Vec<Move> {
-        if self.turn >= N_MOVES as u8 { return vec![] }
-        if self.turn == 0 { return vec![Move(0), Move(1), Move(4)]; };
+    fn get_strategy(&mut self, realization_weight: f32, contempt: f32) -> [f32; MAX_CELLS] {
+        let (strategy, played) = self.current_strategy(contempt);
+        for (sum, s) in self.strategy_sum.iter_mut().zip(strategy) {
+            *sum += s * realization_weight
+        }
+        played
+    }
 
-        let mut out = vec![];
-        for (i, cell) in self.cells.iter().enumerate() {
-            // symmetries on the board
+    // the strategy from regret matching, which is what gets averaged, and the one to play, with contempt mixed in
+    fn current_strategy(&self, contempt: f32) -> ([f32; MAX_CELLS], [f32; MAX_CELLS]) {
+        let mut strategy = [0.0; MAX_CELLS];
+        for (s, r) in strategy.iter_mut().zip(self.regret_sum) { *s = r.max(0.0) }
+        self._normalize(&mut strategy);
 
-            if let CellValue::Empty = cell { 
-                out.push(Move(i))
+        let mut played = strategy;
+        if contempt > 0.0 {
+            let n_legal_moves = self.legal.iter().filter(|i| **i).count();
+            for (s, legal) in played.iter_mut().zip(self.legal) {
+                if legal { *s = *s * (1.0 - contempt) + contempt / n_legal_moves as f32 }
             }
+            self._normalize(&mut played);
         }
-        return out;
+        (strategy, played)
     }
 
-    fn play(&mut self, m: Move) {
-        assert!(m.0 < self.cells.len() && self.cells[m.0] == CellValue::Empty);
-        self.cells[m.0] = self.next_to_move();
-        self.turn += 1
+    fn get_average_strategy(&self) -> [f32; MAX_CELLS] {
+        let mut strat = self.strategy_sum;
+        self._normalize(&mut strat);
+        strat
     }
 
-    fn score(&self) -> Option<(Outcome, i8, i8)> {
-        let all_eq = |a, b, c, d| a == b && b == c && c == d;
-
-        let mut outcome = None;
-        for (p, possible_outcome) in [(CellValue::P0, Outcome::P0Win), (CellValue::P1, Outcome::P1Win)] {
-            if 
-                all_eq(p, self.cells[0], self.cells[1], self.cells[2]) || 
-                all_eq(p, self.cells[3], self.cells[4], self.cells[5]) || 
-                all_eq(p, self.cells[6], self.cells[7], self.cells[8]) || 
+    fn discount(&mut self, positive: f32, negative: f32, strategy: f32) {
+        for r in self.regret_sum.iter_mut() {
+            *r *= if *r > 0.0 { positive } else { negative };
+        }
+        for s in self.strategy_sum.iter_mut() {
+            *s *= strategy;
+        }
+    }
 
-                all_eq(p, self.cells[0], self.cells[3], self.cells[6]) || 
-                all_eq(p, self.cells[1], self.cells[4], self.cells[7]) || 
-                all_eq(p, self.cells[2], self.cells[5], self.cells[8]) || 
+    fn _normalize(&self, strategy: &mut [f32; MAX_CELLS]) {
+        let total_points: f32 = strategy.iter().sum();
+        if total_points == 0.0 {
+            let mut n_possible_actions = 0;
+            for l  in self.legal { if l { n_possible_actions += 1; } }
 
-                all_eq(p, self.cells[0], self.cells[4], self.cells[8]) || 
-                all_eq(p, self.cells[2], self.cells[4], self.cells[6])
-            {
-                outcome = Some(possible_outcome);
-                break;
+            for (s, legal) in strategy.iter_mut().zip(self.legal) {
+                *s = 0.0;
+                if legal {
+                    *s = 1.0/n_possible_actions as f32;
+                }
+            }
+        } else {
+            for s in strategy.iter_mut() {
+                *s /= total_points;
             }
         }
+    }
+}
 
-        if self.turn >= N_MOVES as u8 && outcome == None {
-            outcome = Some(Outcome::Tie);
-        }
+// Contempt starts high so every line gets explored, then drops linearly to a floor
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct Contempt {
+    iterations: u64, // Iterations for contempt to drop to a very very low number
+    start: f32,
+    floor: f32,
+}
 
-        if let Some(o) = outcome {
-            return Some((o, (self.p0_wants == o) as i8, (self.p1_wants == o) as i8))
-        }
-        return None
+impl Contempt {
+    const DEFAULT: Contempt = Contempt { iterations: 10000, start: 0.5, floor: 0.01 };
+
+    fn at(&self, iteration: u64) -> f32 {
+        (self.start * (1.0 - iteration as f32 / self.iterations as f32)).max(self.floor)
     }
 }
 
-// == mapper ==
-impl Board {
-    pub fn to_base_infoset(&self) -> Infoset {
-        Infoset {
-            p0_private: self.p0_wants.to_smallint(),
-            p1_private: self.p1_wants.to_smallint(),
-            history: 1
-        }
+// How each iteration's regrets and strategies get folded into the running sums. Everything but vanilla
+// is a discount applied to every infoset after the iteration (see Brown & Sandholm, "Solving Imperfect-
+// Information Games via Discounted Regret Minimization"), which keeps the sums from growing like t^2.
+// Updates are simultaneous for all of them, CFR+ included.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum UpdateRule {
+    Vanilla,
+    CfrPlus,  // regrets floored at zero, strategies averaged with weight t
+    Linear,  // regrets and strategies both weighted by t
+    Discounted { alpha: f32, beta: f32, gamma: f32 },  // positive regrets by t^alpha, negative by t^beta, strategies by t^gamma
+}
+
+impl UpdateRule {
+    // the paper's recommended DCFR parameters
+    const DCFR: UpdateRule = UpdateRule::Discounted { alpha: 1.5, beta: 0.0, gamma: 2.0 };
+
+    fn floors_regrets(self) -> bool {
+        self == UpdateRule::CfrPlus
     }
 
-    pub fn to_state(&self) -> State {
-        let mut value = 0;
+    // what to scale (positive regrets, negative regrets, strategy sums) by after iteration `t`, counting from 1
+    fn discounts(self, t: u64) -> (f32, f32, f32) {
+        let t = t as f64;
+        let linear = t / (t + 1.0);
+        let (positive, negative, strategy) = match self {
+            UpdateRule::Vanilla => (1.0, 1.0, 1.0),
+            UpdateRule::CfrPlus => (1.0, 1.0, linear),
+            UpdateRule::Linear => (linear, linear, linear),
+            UpdateRule::Discounted { alpha, beta, gamma } => {
+                let (ta, tb) = (t.powf(alpha as f64), t.powf(beta as f64));
+                (ta / (ta + 1.0), tb / (tb + 1.0), linear.powf(gamma as f64))
+            }
+        };
+        (positive as f32, negative as f32, strategy as f32)
+    }
+}
 
-        value *= 3;
-        value += self.p0_wants.to_smallint();
-        value *= 3;
-        value += self.p1_wants.to_smallint();
+impl FromStr for UpdateRule {
+    type Err = String;
 
-        for i in self.cells {
-            value *= 3;
-            value += i.to_smallint();
+    fn from_str(text: &str) -> Result<UpdateRule, String> {
+        let (name, params) = match text.split_once(':') {
+            Some((name, params)) => (name, Some(params)),
+            None => (text, None),
+        };
+        match (name, params) {
+            ("vanilla", None) => Ok(UpdateRule::Vanilla),
+            ("cfr+", None) => Ok(UpdateRule::CfrPlus),
+            ("linear", None) => Ok(UpdateRule::Linear),
+            ("dcfr", None) => Ok(UpdateRule::DCFR),
+            ("dcfr", Some(params)) => {
+                let values = params.split(',')
+                    .map(|v| v.trim().parse::<f32>().map_err(|e| format!("{:?}: {}", v, e)))
+                    .collect::<Result<Vec<f32>, String>>()?;
+                if values.iter().any(|v| !v.is_finite()) { return Err(format!("dcfr's parameters have to be numbers, not {:?}", params)) }
+                match values[..] {
+                    [alpha, beta, gamma] => Ok(UpdateRule::Discounted { alpha, beta, gamma }),
+                    _ => Err(format!("dcfr takes alpha,beta,gamma, not {} values", values.len())),
+                }
+            }
+            _ => Err(format!("unknown update rule {:?}: try vanilla, cfr+, linear or dcfr[:alpha,beta,gamma]", text)),
         }
-
-        State(value)
     }
 }
 
-impl CellValue {
-    fn to_smallint(self) -> u32 {
+impl fmt::Display for UpdateRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            CellValue::Empty => 0,
-            CellValue::P0 => 1,
-            CellValue::P1 => 2,
+            UpdateRule::Vanilla => write!(f, "vanilla"),
+            UpdateRule::CfrPlus => write!(f, "cfr+"),
+            UpdateRule::Linear => write!(f, "linear"),
+            UpdateRule::Discounted { alpha, beta, gamma } => write!(f, "dcfr:{},{},{}", alpha, beta, gamma),
+        }
+    }
+}
+
+// How much of the tree each iteration walks. Full is the CFR this started as. The others are Monte Carlo
+// CFR (Lanctot et al., "Monte Carlo Sampling for Regret Minimization in Extensive Games"): an iteration
+// deals one random pair of wincons for each seat and walks only part of that game, updating that seat's
+// regrets. They need far more iterations, but each one is tiny, so bigger boards become trainable.
+// The discounting rules still touch every infoset once per iteration, so they're slow to combine with these.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Sampling {
+    Full,
+    External,  // every move for the seat being trained; one sampled move for the opponent
+    Outcome { exploration: f32 },  // a single line of play, with the seat being trained playing at random this often
+}
+
+impl FromStr for Sampling {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Sampling, String> {
+        match text.split_once(':') {
+            None if text == "full" => Ok(Sampling::Full),
+            None if text == "external" => Ok(Sampling::External),
+            None if text == "outcome" => Ok(Sampling::Outcome { exploration: 0.6 }),
+            Some(("outcome", exploration)) => match exploration.trim().parse::<f32>() {
+                Ok(exploration) if (0.0..=1.0).contains(&exploration) => Ok(Sampling::Outcome { exploration }),
+                _ => Err(format!("{:?} isn't an exploration rate between 0 and 1", exploration)),
+            },
+            _ => Err(format!("unknown sampling {:?}: try full, external or outcome[:exploration]", text)),
         }
     }
 }
 
-impl Outcome {
-    fn to_smallint(self) -> u32 {
+impl fmt::Display for Sampling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Outcome::Tie => 0,
-            Outcome::P0Win => 1,
-            Outcome::P1Win => 2,
+            Sampling::Full => write!(f, "full"),
+            Sampling::External => write!(f, "external"),
+            Sampling::Outcome { exploration } => write!(f, "outcome:{}", exploration),
         }
     }
 }
 
+// everything learned so far, by (history, private) like the strategy file
+type Infosets = HashMap<(InfosetKey, u32), InfosetNode>;
 
-fn map_game() -> CFR {
-    let mut reached: HashSet<State> = HashSet::new();
-    let mut state_initial: HashMap<State, Infoset> = HashMap::new();
-    let mut state_edges: HashMap<State, [Option<State>; N_MOVES]> = HashMap::new();
-    let mut state_score: HashMap<State, (i8, i8)> = HashMap::new();
+#[derive(Debug, Serialize, Deserialize)]
+struct Cfr {
+    trained_iterations: u64,
+    geometry: Geometry,
+    contempt: Contempt,
+    rule: UpdateRule,
+    sampling: Sampling,
+    seed: u64,
+    #[serde(serialize_with = "serialize_sorted")]
+    infosets: Infosets,
+}
 
-    let mut queue: VecDeque<(Option<(State, Move)>, Board)> = VecDeque::new();
-    for start in Board::possible_starts() {
-        queue.push_back((None, start))
+// a HashMap comes out in a different order every run: sort it so the same training saves the same bytes
+fn serialize_sorted<S: Serializer>(infosets: &Infosets, serializer: S) -> Result<S::Ok, S::Error> {
+    let mut entries: Vec<_> = infosets.iter().collect();
+    entries.sort_unstable_by_key(|(key, _)| **key);
+    serializer.collect_map(entries)
+}
+
+impl Cfr {
+    fn new(geometry: Geometry) -> Cfr {
+        Cfr { 
+            trained_iterations: 0, 
+            geometry, 
+            contempt: Contempt::DEFAULT, 
+            rule: UpdateRule::Vanilla, 
+            sampling: Sampling::Full, 
+            seed: 0, 
+            infosets: HashMap::new(),
+        }
     }
 
-    while let Some((predecessor, board)) = queue.pop_front() {
-        let state = board.to_state();
+    fn with_infoset_node<T>(&mut self, board: &Board, f: impl FnOnce(&mut InfosetNode) -> T) -> T {
+        with_node(&mut self.infosets, board, f)
+    }
 
-        match predecessor {
-            None => { state_initial.insert(state, board.to_base_infoset()); }
-            Some((pred, m)) => {
-                state_edges.entry(pred).or_insert_with(|| [None; N_MOVES])[m.0] = Some(state);
-            }
+    // `threads` only matters for full walks: the others are sequential
+    fn train(&mut self, contempt: f32, threads: usize) -> f32 {
+        let util = match self.sampling {
+            Sampling::Full => self.train_full(contempt, threads),
+            sampling => self.train_sampled(sampling, contempt),
+        };
+        self.trained_iterations += 1;
+        self.discount();
+        util
+    }
+
+    // One thread walks the deals in order, updating as it goes. More than that split the deals between them, all
+    // playing this iteration's starting strategies and collecting their updates on the side. Those get added in
+    // afterwards in thread order, so a run comes out the same every time for the same number of threads.
+    fn train_full(&mut self, contempt: f32, threads: usize) -> f32 {
+        let initial = possible_starts(self.geometry);
+        let threads = threads.clamp(1, initial.len());
+        let floor = self.rule.floors_regrets();
+
+        if threads == 1 {
+            let mut walk = InPlace { infosets: &mut self.infosets, floor };
+            let util: f32 = initial.iter().map(|init_board| walk_full(&mut walk, init_board, contempt, 1.0, 1.0)).sum();
+            return util / initial.len() as f32
         }
 
-        if reached.contains(&state) { continue; }
-        reached.insert(state);
+        let infosets = &self.infosets;
+        let results: Vec<(f32, Infosets)> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads).map(|t| {
+                let starts = &initial;
+                scope.spawn(move || {
+                    let mut walk = Deferred { infosets, updates: HashMap::new() };
+                    let mut util = 0.0;
+                    for init_board in starts.iter().skip(t).step_by(threads) {
+                        util += walk_full(&mut walk, init_board, contempt, 1.0, 1.0);
+                    }
+                    (util, walk.updates)
+                })
+            }).collect();
+            workers.into_iter().map(|w| w.join().expect("training thread panicked")).collect()
+        });
 
-        if let Some(s) = board.score() {
-            state_score.insert(state, (s.1, s.2));
+        let mut util = 0.0;
+        for (thread_util, updates) in results {
+            util += thread_util;
+            for (key, update) in updates {
+                let node = self.infosets.entry(key).or_insert(InfosetNode { 
+                    legal: update.legal, regret_sum: [0.0; MAX_CELLS], strategy_sum: [0.0; MAX_CELLS] 
+                });
+                assert!(node.legal == update.legal);
+                for m in 0..MAX_CELLS {
+                    node.regret_sum[m] += update.regret_sum[m];
+                    node.strategy_sum[m] += update.strategy_sum[m];
+                }
+            }
         }
-
-        for m in board.possible_moves() {
-            let mut b2 = board;
-            b2.play(m);
-            queue.push_back((Some((state, m)), b2));
+        // CFR+ floors the iteration's total, not each thread's share of it
+        if floor {
+            for node in self.infosets.values_mut() {
+                for r in node.regret_sum.iter_mut() { *r = r.max(0.0) }
+            }
         }
+        util / initial.len() as f32
     }
 
-    let mut initial: Vec<(State, Infoset)> = state_initial.iter().map(|(x, y)| (*x, *y)).collect();
-    initial.sort_unstable_by_key(|(s, _)| s.0);
+    // returns p0's utility in the game it got dealt. very noisy, but it averages out
+    fn train_sampled(&mut self, sampling: Sampling, contempt: f32) -> f32 {
+        let mut rng = self.iteration_rng();
+        let starts = possible_starts(self.geometry);
+        let mut util = 0.0;
+        for traverser in [CellValue::P0, CellValue::P1] {
+            let start = starts.choose(&mut rng).unwrap();
+            let u = match sampling {
+                Sampling::Full => unreachable!("full walks aren't sampled"),
+                Sampling::External => self.walk_external(start, traverser, contempt, &mut rng),
+                Sampling::Outcome { exploration } => {
+                    let (u, tail) = self.walk_outcome(start, traverser, contempt, exploration, (1.0, 1.0, 1.0), &mut rng);
+                    u * tail
+                }
+            };
+            if traverser == CellValue::P0 { util = u }
+        }
+        util
+    }
 
-    let max_state = reached.iter().max_by_key(|s| s.0).expect("expected at least one state");
+    // every iteration gets its own generator, so a run samples the same games however it's split up between saves
+    fn iteration_rng(&self) -> StdRng {
+        StdRng::seed_from_u64(self.seed ^ self.trained_iterations.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+    }
 
-    let mut states: Vec<Option<StateNode>> = vec![None; max_state.0 as usize + 1];
+    fn discount(&mut self) {
+        let (positive, negative, strategy) = self.rule.discounts(self.trained_iterations);
+        if (positive, negative, strategy) == (1.0, 1.0, 1.0) { return }
 
-    for s in reached {
-        let successors = state_edges.get(&s).map(|x| *x).unwrap_or_default();
-        states[s.0 as usize] = Some(StateNode {
-            successors,
-            score: state_score.get(&s).cloned(),
-        });
+        for node in self.infosets.values_mut() {
+            node.discount(positive, negative, strategy);
+        }
     }
 
-    CFR { trained_iterations: 0, initial, states, infosets: HashMap::new() }
-}
-
-// CFR
-#[derive(Clone, Debug, Serialize, Deserialize)]  // no Copy: you probably don't want to copy these as they are frequently mutated in place
-struct InfosetNode {
-    legal: [bool; N_MOVES],
-    regret_sum: [f32; N_MOVES],
-    strategy_sum: [f32; N_MOVES],
-}
+    // external sampling: returns the traverser's utility for the sampled opponent moves
+    fn walk_external(&mut self, board: &Board, traverser: CellValue, contempt: f32, rng: &mut StdRng) -> f32 {
+        if let Some(utility) = terminal_utility(board, contempt, traverser) {
+            return utility
+        }
 
-impl InfosetNode {
-    fn get_strategy(&mut self, realization_weight: f32, contempt: f32) -> [f32; N_MOVES] {
-        let mut strategy = [0.0; N_MOVES];
-        for i in 0..N_MOVES { strategy[i] = self.regret_sum[i].max(0.0) }
-        self._normalize(&mut strategy);
-        for i in 0..N_MOVES {
-            self.strategy_sum[i] += strategy[i] * realization_weight
+        let moves = board.possible_moves();
+        if board.next_to_move() != traverser {
+            // the opponent's average strategy gets one visit's worth of its current strategy
+            let strategy = self.with_infoset_node(board, |n| n.get_strategy(1.0, contempt));
+            let mut successor = board.clone();
+            successor.play(sample_move(rng, strategy, moves));
+            return self.walk_external(&successor, traverser, contempt, rng)
         }
 
-        if contempt > 0.0 {
-            let n_legal_moves = self.legal.iter().filter(|i| **i).count();
-            for i in 0..N_MOVES {
-                if self.legal[i] { strategy[i] = strategy[i] * (1.0 - contempt) + contempt / n_legal_moves as f32 }
-            }
-            self._normalize(&mut strategy);
+        let strategy = self.with_infoset_node(board, |n| n.get_strategy(0.0, contempt));
+        let mut util = [0.0; MAX_CELLS];
+        let mut node_util = 0.0;
+        for m in moves.iter().map(Move::index) {
+            let mut successor = board.clone();
+            successor.play(Move::new(m));
+            util[m] = self.walk_external(&successor, traverser, contempt, rng);
+            node_util += strategy[m] * util[m];
         }
-        strategy
-    }
 
-    fn get_average_strategy(&self) -> [f32; N_MOVES] {
-        let mut strat = self.strategy_sum;
-        self._normalize(&mut strat);
-        strat
+        // sampling the opponent already weights these by how likely they were to get here
+        self.add_regrets(board, |m| util[m] - node_util);
+        node_util
     }
 
-    fn _normalize(&self, strategy: &mut [f32; N_MOVES]) {
-        let total_points: f32 = strategy.iter().sum();
-        if total_points == 0.0 {
-            let mut n_possible_actions = 0;
-            for l  in self.legal { if l { n_possible_actions += 1; } }
+    // outcome sampling. `reach` is (the traverser's reach, the opponent's reach, the chance this line got sampled).
+    // returns the traverser's utility over the sampling chance, and how likely the rest of the line was under the
+    // current strategies
+    fn walk_outcome(
+        &mut self, 
+        board: &Board, 
+        traverser: CellValue, 
+        contempt: f32, 
+        exploration: f32, 
+        reach: (f32, f32, f32), 
+        rng: &mut StdRng
+    ) -> (f32, f32) {
+        let (reach_mine, reach_theirs, sample) = reach;
+        if let Some(utility) = terminal_utility(board, contempt, traverser) {
+            return (utility / sample, 1.0)
+        }
 
-            for i in 0..N_MOVES {
-                strategy[i] = 0.0;
-                if self.legal[i] {
-                    strategy[i] = 1.0/n_possible_actions as f32;
-                }
-            }
-        } else {
-            for i in 0..N_MOVES {
-                strategy[i] /= total_points;
+        let moves = board.possible_moves();
+        let mine = board.next_to_move() == traverser;
+
+        // the opponent's average strategy is weighted by its reach, over the chance of sampling it
+        let strategy = self.with_infoset_node(board, |n| n.get_strategy(if mine { 0.0 } else { reach_theirs / sample }, contempt));
+        let mut sampling = strategy;
+        if mine {
+            for m in moves.iter().map(Move::index) {
+                sampling[m] = exploration / moves.len() as f32 + (1.0 - exploration) * strategy[m];
             }
         }
+
+        let m = sample_move(rng, sampling, moves).index();
+        let mut successor = board.clone();
+        successor.play(Move::new(m));
+        let reach = if mine { 
+            (reach_mine * strategy[m], reach_theirs, sample * sampling[m]) 
+        } else { 
+            (reach_mine, reach_theirs * strategy[m], sample * sampling[m]) 
+        };
+        let (util, tail) = self.walk_outcome(&successor, traverser, contempt, exploration, reach, rng);
+
+        if mine {
+            let w = util * reach_theirs;
+            self.add_regrets(board, |b| if b == m { w * tail * (1.0 - strategy[m]) } else { -w * tail * strategy[m] });
+        }
+        (util, tail * strategy[m])
+    }
+
+    fn add_regrets(&mut self, board: &Board, regret: impl Fn(usize) -> f32) {
+        let floor = self.rule.floors_regrets();
+        let moves = board.possible_moves();
+        self.with_infoset_node(board, |n| {
+            for m in moves.iter().map(Move::index) {
+                n.regret_sum[m] += regret(m);
+                if floor { n.regret_sum[m] = n.regret_sum[m].max(0.0) }
+            }
+        })
     }
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
-struct StateNode {
-    successors: [Option<State>; N_MOVES],
-    score: Option<(i8, i8)>,
+// Where a full walk gets its strategies, and where it puts what it learns
+trait Walk {
+    // (the strategy that gets averaged, the one that gets played) for whoever's moving on `board`
+    fn strategy(&self, board: &Board, contempt: f32) -> ([f32; MAX_CELLS], [f32; MAX_CELLS]);
+    fn update(&mut self, board: &Board, f: impl FnOnce(&mut InfosetNode));
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct CFR {
-    trained_iterations: u64,
-    initial: Vec<(State, Infoset)>,
-    states: Vec<Option<StateNode>>,
-    infosets: HashMap<(u32, u32), InfosetNode>,
+// straight into the table, so later deals in the same iteration see the updates
+struct InPlace<'a> {
+    infosets: &'a mut Infosets,
+    floor: bool,
 }
 
-impl CFR {
-    fn with_infoset_node<T>(&mut self, infoset: Infoset, for_p0: bool, state: &StateNode, f: impl FnOnce(&mut InfosetNode) -> T) -> T {
-        let mut legal = [false; N_MOVES];
-        for i in 0..N_MOVES {
-            if let Some(_) = state.successors[i] { legal[i] = true; }
-        }
+impl Walk for InPlace<'_> {
+    fn strategy(&self, board: &Board, contempt: f32) -> ([f32; MAX_CELLS], [f32; MAX_CELLS]) {
+        current_strategy(self.infosets, board, contempt)
+    }
 
-        let key = (infoset.history, if for_p0 { infoset.p0_private } else { infoset.p1_private });
-        match self.infosets.entry(key) {
-            Entry::Occupied(mut o) => {
-                let iset =  o.get_mut();
-                assert!(iset.legal == legal);
-                f(iset)
-            }
-            Entry::Vacant(v) =>  {
-                f(v.insert(InfosetNode { legal, regret_sum: [0.0; N_MOVES], strategy_sum: [0.0; N_MOVES]}))
-            }
-        }
+    fn update(&mut self, board: &Board, f: impl FnOnce(&mut InfosetNode)) {
+        let floor = self.floor;
+        with_node(self.infosets, board, |n| {
+            f(n);
+            if floor { for r in n.regret_sum.iter_mut() { *r = r.max(0.0) } }
+        })
     }
+}
 
-    fn train(&mut self, contempt: f32) -> f32 {
-        let initial = self.initial.clone();
-        let mut util = 0.0;
-        for (init_state, init_infoset) in initial.iter() {
-            util += self._train(*init_state, *init_infoset, contempt, 0, 1.0, 1.0);
-        }
-        util /= initial.len() as f32;
-        self.trained_iterations += 1;
-        return util;
+// strategies from a table nobody's changing, and updates kept separately to add in later
+struct Deferred<'a> {
+    infosets: &'a Infosets,
+    updates: Infosets,
+}
+
+impl Walk for Deferred<'_> {
+    fn strategy(&self, board: &Board, contempt: f32) -> ([f32; MAX_CELLS], [f32; MAX_CELLS]) {
+        current_strategy(self.infosets, board, contempt)
     }
 
-    fn _train(&mut self, state: State, infoset: Infoset, contempt: f32, turn: usize, p0: f32, p1: f32) -> f32 {
-        let player = turn % 2;
-        let node = self.states[state.0 as usize].expect("missing state");
+    fn update(&mut self, board: &Board, f: impl FnOnce(&mut InfosetNode)) {
+        with_node(&mut self.updates, board, f)
+    }
+}
+
+fn current_strategy(infosets: &Infosets, board: &Board, contempt: f32) -> ([f32; MAX_CELLS], [f32; MAX_CELLS]) {
+    match infosets.get(&board.infoset_key(board.next_to_move())) {
+        Some(node) => node.current_strategy(contempt),
+        None => InfosetNode::new(board).current_strategy(contempt),
+    }
+}
+
+fn walk_full(walk: &mut impl Walk, board: &Board, contempt: f32, p0: f32, p1: f32) -> f32 {
+    let player = board.turn() % 2;
 
-        if let Some((sc_p0, sc_p1)) = node.score {
-            let mut sc_p0_adjusted = sc_p0 as f32;
-            let mut sc_p1_adjusted = sc_p1 as f32;
+    if let Some(utility) = terminal_utility(board, contempt, board.next_to_move()) {
+        return utility
+    }
 
-            // strongly prefer to win in fewer turns
-            // this probably isn't good for its overall play, but _is_ more humanlike
-            let adj_turn = (turn / 2) as f32;
-            let adj_turn_multiplier = (4.0 - adj_turn).max(0.0)/4.0;
-            if sc_p0_adjusted > 0.0 { sc_p0_adjusted += contempt * adj_turn_multiplier }
-            if sc_p1_adjusted > 0.0 { sc_p1_adjusted += contempt * adj_turn_multiplier }
+    let (averaged, strategy) = walk.strategy(board, contempt);
 
-            let mut utility = (sc_p0_adjusted - sc_p1_adjusted) as f32;
-            if player == 1 { utility = -utility }
-            return utility
+    let mut util = [0.0; MAX_CELLS];
+    let mut node_util = 0.0;
+
+    let moves = board.possible_moves();
+    for m in moves.iter().map(Move::index) {
+        let mut successor = board.clone();
+        successor.play(Move::new(m));
+        util[m] = if player == 0 {
+            -walk_full(walk, &successor, contempt, p0 * strategy[m], p1)
+        } else {
+            -walk_full(walk, &successor, contempt, p0, p1 * strategy[m])
+        };
+        node_util += strategy[m] * util[m];
+    }
+
+    // counterfactual regret: weighted by how likely everyone *else* was to get here
+    let (reach_mine, reach_theirs) = if player == 0 { (p0, p1) } else { (p1, p0) };
+    walk.update(board, |n| {
+        for m in moves.iter().map(Move::index) {
+            n.regret_sum[m] += reach_theirs * (util[m] - node_util);
         }
+        for (sum, s) in n.strategy_sum.iter_mut().zip(averaged) {
+            *sum += s * reach_mine;
+        }
+    });
 
-        let strategy = 
-            self.with_infoset_node(infoset, player == 0, &node, |n| 
-                n.get_strategy(if player == 0 { p0 } else { p1 }, contempt)
-            );
+    node_util
+}
+
+fn with_node<T>(infosets: &mut Infosets, board: &Board, f: impl FnOnce(&mut InfosetNode) -> T) -> T {
+    let key = board.infoset_key(board.next_to_move());
+    match infosets.entry(key) {
+        Entry::Occupied(mut o) => {
+            let iset =  o.get_mut();
+            assert!(iset.legal == InfosetNode::new(board).legal);
+            f(iset)
+        }
+        Entry::Vacant(v) =>  {
+            f(v.insert(InfosetNode::new(board)))
+        }
+    }
+}
+
+// the zero-sum utility of a finished game for `seat`
+fn terminal_utility(board: &Board, contempt: f32, seat: CellValue) -> Option<f32> {
+    let (_, sc_p0, sc_p1) = board.score()?;
+    let mut sc_p0_adjusted = sc_p0 as f32;
+    let mut sc_p1_adjusted = sc_p1 as f32;
+
+    // strongly prefer to win in fewer turns
+    // this probably isn't good for its overall play, but _is_ more humanlike
+    let adj_turn = (board.turn() / 2) as f32;
+    let adj_turn_multiplier = (4.0 - adj_turn).max(0.0)/4.0;
+    if sc_p0_adjusted > 0.0 { sc_p0_adjusted += contempt * adj_turn_multiplier }
+    if sc_p1_adjusted > 0.0 { sc_p1_adjusted += contempt * adj_turn_multiplier }
+
+    let utility = sc_p0_adjusted - sc_p1_adjusted;
+    Some(if seat == CellValue::P0 { utility } else { -utility })
+}
 
-        let mut util = [0.0; N_MOVES];
-        let mut node_util = 0.0;
 
-        for m in 0..N_MOVES {
-            if let Some(successor) = node.successors[m] {
-                util[m] = if player == 0 {
-                    -self._train(successor, infoset.cons(Move(m)), contempt, turn + 1, p0 * strategy[m], p1)
-                } else {
-                    -self._train(successor, infoset.cons(Move(m)), contempt, turn + 1, p0, p1 * strategy[m])
-                };
-                node_util += strategy[m] * util[m];
+fn main() {
+    match Cli::parse().command {
+        Command::Train { 
+            cfr, iterations, contempt_iterations, contempt_start, contempt_floor, save_every, rule, report_every,
+            sampling, seed, board, log_every, threads,
+        } => {
+            let path = &cfr;
+            let ControlFlow::Continue(mut cfr) = load_cfr(path, Some(board.unwrap_or(Geometry::STANDARD))) else { return };
+            if board.is_some_and(|b| b != cfr.geometry) {
+                println!("... that checkpoint is for a {} board. noping out", describe_geometry(cfr.geometry));
+                return
+            }
+            let old = cfr.contempt;
+            cfr.contempt = Contempt {
+                iterations: contempt_iterations.unwrap_or(old.iterations),
+                start: contempt_start.unwrap_or(old.start),
+                floor: contempt_floor.unwrap_or(old.floor),
+            };
+            cfr.rule = rule.unwrap_or(cfr.rule);
+            cfr.sampling = sampling.unwrap_or(cfr.sampling);
+            cfr.seed = seed.unwrap_or(cfr.seed);
+            let log_every = log_every.unwrap_or(if cfr.sampling == Sampling::Full { 1 } else { 10000 }).max(1);
+            if threads > 1 && cfr.sampling != Sampling::Full {
+                println!("... only full walks can use more than one thread. noping out");
+                return
             }
-        }
 
-        for m in 0..N_MOVES {
-            if let Some(_) = node.successors[m] {  // if the move was legal
-                let regret = util[m] - node_util;
-                self.with_infoset_node(infoset, player == 0, &node, |n| {
-                    n.regret_sum[m] += if player == 0 { p0 } else { p1 } * regret;
-                })
+            let mut total_util = 0.0;
+            while cfr.trained_iterations < iterations {
+                let logging = cfr.trained_iterations % log_every == 0;
+                if logging { println!("training: iteration {}", cfr.trained_iterations); }
+                let contempt = cfr.contempt.at(cfr.trained_iterations);
+                total_util += cfr.train(contempt, threads);
+                if cfr.trained_iterations % log_every == 0 {
+                    println!("average utility: {}", total_util / log_every as f32);
+                    total_util = 0.0;
+                }
+
+                if report_every.is_some_and(|n| n != 0 && cfr.trained_iterations % n == 0) {
+                    report_exploitability(&cfr);
+                }
+                if save_every != 0 && cfr.trained_iterations % save_every == 0 {
+                    if let ControlFlow::Break(_) = save_cfr(&cfr, path) { return; }
+                }
             }
+            let _ = save_cfr(&cfr, path);
+        }
+        Command::Export { cfr, out, val_expansion } => {
+            let ControlFlow::Continue(cfr) = load_cfr(&cfr, None) else { return };
+            let strategydata = match export_strategy(&cfr, val_expansion.unwrap_or(VAL_EXPANSION)) {
+                Ok(data) => data,
+                Err(e) => { println!("... could not encode strategy! {}", e); return }
+            };
+            match std::fs::write(&out, strategydata) {
+                Ok(_) => println!("... exported strategy to {}!", out.display()),
+                Err(e) => { println!("... could not export strategy! {}", e)}
+            }
+        }
+        Command::Play { cfr, seed } => {
+            let ControlFlow::Continue(mut cfr) = load_cfr(&cfr, None) else { return };
+            let mut rng = match seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            };
+            play_game(&mut rng, &mut cfr);
+        }
+        Command::Inspect { cfr: _, strategy: Some(path), position } => {
+            inspect_strategy(&path, position.as_ref());
+        }
+        Command::Inspect { cfr, strategy: None, position } => {
+            let ControlFlow::Continue(mut cfr) = load_cfr(&cfr, None) else { return };
+            inspect_cfr(&mut cfr, position.as_ref());
         }
-
-        node_util
     }
 }
 
+// Checkpoints are the magic, a version, then `Cfr` in bincode. Bump the version whenever `Cfr` changes shape:
+// bincode has no field names, so an old checkpoint would otherwise fail to load with a meaningless error
+const CHECKPOINT_MAGIC: [u8; 4] = *b"TT2C";
+const CHECKPOINT_VERSION: u8 = 4;
 
-fn main() {
-    let path = Path::new("cfr.dat");
+fn encode_checkpoint(cfr: &Cfr) -> Result<Vec<u8>, bincode::Error> {
+    let mut out = CHECKPOINT_MAGIC.to_vec();
+    out.push(CHECKPOINT_VERSION);
+    bincode::serialize_into(&mut out, cfr)?;
+    Ok(out)
+}
+
+fn decode_checkpoint(data: &[u8]) -> Result<Cfr, String> {
+    let Some(rest) = data.strip_prefix(&CHECKPOINT_MAGIC) else {
+        return Err(
+            "it's from a trainer that didn't version its checkpoints, so this one can't read it. \
+            export it with the trainer that made it, or move it aside and train a new one".to_string()
+        )
+    };
+    match rest.split_first() {
+        Some((&CHECKPOINT_VERSION, cfr)) => bincode::deserialize(cfr).map_err(|e| e.to_string()),
+        Some((version, _)) => Err(format!("it's checkpoint version {}, and this trainer only reads version {}", version, CHECKPOINT_VERSION)),
+        None => Err("it stops right after the magic number".to_string()),
+    }
+}
+
+// `create` is the board to start a new checkpoint on if there isn't one
+fn load_cfr(path: &Path, create: Option<Geometry>) -> ControlFlow<(), Cfr> {
     println!("loading CFR data");
-    let mut cfr = match std::fs::read(&path) {
-        Ok(data) => match bincode::deserialize::<CFR>(&data) {
-            Ok(cfr) => cfr,
+    match std::fs::read(path) {
+        Ok(data) => match decode_checkpoint(&data) {
+            Ok(cfr) => ControlFlow::Continue(cfr),
             Err(err) => {
                 println!("... couldn't load CFR data, but found file; noping out ({})", err);
-                return
+                ControlFlow::Break(())
             }
         }
         Err(err) => {
             match path.try_exists() {
                 Ok(true) => { 
                     println!("... couldn't load CFR data, but file definitely exists. noping out ({})", err); 
-                    return 
+                    ControlFlow::Break(())
                 }
-                Ok(false) => {
+                Ok(false) if create.is_some() => {
                     println!("CFR data doesn't exist.");
-                    println!("mapping game...");
-                    let cfr = map_game();
+                    let cfr = Cfr::new(create.unwrap());
 
-                    if let ControlFlow::Break(_) = save_cfr(&cfr, path) { return; }
-                    cfr
+                    save_cfr(&cfr, path)?;
+                    ControlFlow::Continue(cfr)
+                }
+                Ok(false) => {
+                    println!("... CFR data doesn't exist at {}. train some first", path.display());
+                    ControlFlow::Break(())
                 }
                 Err(err) => {
                     println!("... couldn't figure out if CFR data exists. noping out ({})", err);
-                    return
+                    ControlFlow::Break(())
                 }
             }
         }
-    };
-
-    while cfr.trained_iterations < DESIRED_ITERATIONS {
-        println!("training: iteration {}", cfr.trained_iterations);
-        let contempt = (0.5 * (1.0 - cfr.trained_iterations as f32 / CONTEMPT_ITERATIONS as f32)).max(0.01);
-        let util = cfr.train(contempt);
-        println!("average utility: {}", util);
-
-        if cfr.trained_iterations % SAVE_EVERY == 0 {
-            if let ControlFlow::Break(_) = save_cfr(&cfr, path) { return; }
-        }
     }
-    if let ControlFlow::Break(_) = save_cfr(&cfr, path) { return; }
-
-    // let mut rng = rand::prelude::StdRng::seed_from_u64(4);
-    let mut rng = rand::thread_rng();
-    play_game(&mut rng, &mut cfr);
+}
 
-    let strategydata = export_strategy(&mut cfr);
-    match std::fs::write("strategy.dat", strategydata) {
-        Ok(_) => println!("... exported strategy!"),
-        Err(e) => { println!("... could not export strategy! {}", e)}
+fn export_strategy(cfr: &Cfr, val_expansion: [f32; 16]) -> Result<Vec<u8>, StrategyError> {
+    let mut header = StrategyHeader::current(cfr.geometry, cfr.trained_iterations, cfr.contempt.iterations, cfr.contempt.start, cfr.contempt.floor);
+    header.val_expansion = val_expansion;
+    let mut infosets = vec![];
+    for (key, node) in cfr.infosets.iter() {
+        let trained = TrainedInfoset { legal: node.legal, strategy: node.get_average_strategy() };
+        infosets.push((*key, trained));
     }
+    codec::encode(&header, infosets)
 }
 
-fn export_strategy(cfr: &mut CFR) -> Vec<u8> {
-    fn is_interesting(node: &InfosetNode, strategy: &[f32; N_MOVES]) -> bool {
-        // return whether the distribution is substantially different from picking uniformly at random
-        let mut default_strategy = [0.0; N_MOVES];
-        node._normalize(&mut default_strategy);
+fn describe_geometry(geometry: Geometry) -> String {
+    format!("{}x{}, {} in a row", geometry.width(), geometry.height(), geometry.line_length())
+}
 
-        // calculate bhattacharyya distance
-        let mut bhat = 0.0;
-        for i in 0..N_MOVES {
-            bhat += (default_strategy[i] * strategy[i]).sqrt();
-        }
-        return bhat < 0.9
+fn inspect_cfr(cfr: &mut Cfr, position: Option<&Board>) {
+    println!("board: {}", describe_geometry(cfr.geometry));
+    println!("trained iterations: {}", cfr.trained_iterations);
+    println!("contempt: {} dropping to {} over {} iterations", cfr.contempt.start, cfr.contempt.floor, cfr.contempt.iterations);
+    println!("update rule: {}", cfr.rule);
+    println!("sampling: {} (seed {})", cfr.sampling, cfr.seed);
+    println!("infosets: {}", cfr.infosets.len());
+    report_exploitability(cfr);
+
+    let Some(board) = position else { return };
+    if board.geometry() != cfr.geometry { println!("... that position isn't on this board"); return }
+    if board.is_terminal() { println!("... that game is over"); return }
+    if !cfr.infosets.contains_key(&board.infoset_key(board.next_to_move())) {
+        println!("... training never reached that position");
+        return
     }
+    draw_distribution(board, cfr.with_infoset_node(board, |i| i.get_average_strategy()));
+}
 
-    fn simplify(strategy: [f32; N_MOVES]) -> [u8; N_MOVES] {
-        const POSSIBILITIES: [f32; 16] = [0.0, 0.01, 0.1, 0.2, 0.3, 0.33333, 0.4, 0.5, 0.6, 0.666666, 0.7, 0.8, 0.9, 0.98, 0.99, 1.0];
-
-        fn simplify_term(term: f32) -> u8 {
-            let dist = POSSIBILITIES.map(|p| {
-                ((term - p).abs() * 100000.0) as u32
-            });
+// the average strategy, as something the engine's analysis tools can play
+struct AverageStrategy<'a>(&'a Cfr);
 
-            return (0..POSSIBILITIES.len()).min_by_key(|i| dist[*i]).unwrap() as u8
+impl Policy for AverageStrategy<'_> {
+    fn distribution(&self, board: &Board) -> [f32; MAX_CELLS] {
+        match self.0.infosets.get(&board.infoset_key(board.next_to_move())) {
+            Some(node) => node.get_average_strategy(),
+            None => uniform_over(board.possible_moves()),
         }
-
-        strategy.map(simplify_term)
     }
+}
 
-    let mut out: Vec<u8> = Vec::new();
-    let mut sorted_infosets: Vec<_> = cfr.infosets.iter().collect();
-    sorted_infosets.sort_by_key(|((history, private), _)| (history, private));
-
-    let mut last_tag: u32 = 0;
-
-    for ((history, private), node) in sorted_infosets.iter() {
-        let strategy = node.get_average_strategy();
-        if !is_interesting(node, &strategy) { continue; }
-
-        let simp: [u8; N_MOVES] = simplify(strategy);  // note: must be 9 long
-        let n_nonzero = simp.iter().filter(|x| **x != 0).count() as u32;
-
-        assert!(n_nonzero > 0);
-        assert!(n_nonzero <= 7);
-
-        assert!(history & 0x03ffffff == *history);
-        assert!(private & 0b11 == *private);
-
-        let tag = history << 6 | private << 4 | n_nonzero;
-
-        let diff_tag = tag - last_tag;
+fn report_exploitability(cfr: &Cfr) {
+    // the best response walk isn't memoized, so anything much bigger than 3x3 would take forever
+    if cfr.geometry.n_cells() > Geometry::STANDARD.n_cells() {
+        println!("exploitability: {} is too big to walk", describe_geometry(cfr.geometry));
+        return
+    }
+    let e = exploitability_on(cfr.geometry, &AverageStrategy(cfr));
+    println!("exploitability: {} (best responses {} as p0, {} as p1)", e.total(), e.best_response[0], e.best_response[1]);
+}
 
-        if diff_tag < 64 {
-            let bytes = (diff_tag as u8).to_be_bytes();
-            assert!(bytes[0] & 0b00111111 == bytes[0]);
-            out.extend(bytes);
-        } else if diff_tag < 64 * 256 {
-            let mut bytes = (diff_tag as u16).to_be_bytes();
-            assert!(bytes[0] & 0b00111111 == bytes[0]);
-            bytes[0] |= 0b01000000;
-            // TODO: bitwise tag
-            out.extend(bytes);
-        } else if tag < 128 * 256 * 256 * 256 {
-            let mut bytes = (tag as u32).to_be_bytes();
-            assert!(bytes[0] & 0b01111111 == bytes[0]);
-            bytes[0] |= 0b10000000;
-            out.extend(bytes);
-        } else {
-            panic!("tag is too big: {}", tag);
-        }
-        last_tag = tag;
+fn inspect_strategy(path: &Path, position: Option<&Board>) {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(err) => { println!("... couldn't read strategy ({})", err); return }
+    };
+    // files from before there was a header are still around, e.g. the one the game embeds
+    let legacy = matches!(StrategyHeader::read(&data), Err(StrategyError::BadMagic));
+    let decoded = if legacy { codec::decode_legacy(&data) } else { codec::decode(&data) };
+    let (header, entries) = match decoded {
+        Ok(decoded) => decoded,
+        Err(err) => { println!("... couldn't decode strategy ({})", err); return }
+    };
 
-        for (i, value) in simp.iter().enumerate() {
-            if *value != 0 { out.push((i as u8) << 4 | value) }
+    println!("format version: {}{}", header.version, if legacy { " (no header)" } else { "" });
+    match header.geometry() {
+        Some(geometry) => println!("board: {}", describe_geometry(geometry)),
+        None => println!("board: {}x{}, {} in a row (unsupported)", header.width, header.height, header.line_length),
+    }
+    println!("canonical keys: {}", header.canonical_keys());
+    println!("trained iterations: {}", header.iterations);
+    println!("contempt: {} dropping to {} over {} iterations", header.contempt_start, header.contempt_floor, header.contempt_iterations);
+    println!("quantisation: {:?}", header.val_expansion);
+    println!("entries: {}", entries.len());
+
+    let Some(board) = position else { return };
+    let strategy = if legacy { Strategy::from_legacy_bytes(&data) } else { Strategy::from_bytes(&data) };
+    match strategy {
+        Ok(strategy) if strategy.geometry() == board.geometry() => {
+            if board.is_terminal() { println!("... that game is over"); return }
+            draw_distribution(board, strategy.distribution(board))
         }
+        Ok(_) => println!("... that position isn't on this board"),
+        Err(err) => println!("... the game can't use this strategy ({})", err),
     }
-
-    return out;
 }
 
-fn save_cfr(cfr: &CFR, path: &Path) -> ControlFlow<()> {
-    match bincode::serialize::<CFR>(cfr) {
+fn save_cfr(cfr: &Cfr, path: &Path) -> ControlFlow<()> {
+    match encode_checkpoint(cfr) {
         Ok(o) => {
             match std::fs::write(path, o) {
                 Ok(()) => { println!("... saved!"); }
@@ -515,54 +924,40 @@ fn save_cfr(cfr: &CFR, path: &Path) -> ControlFlow<()> {
     ControlFlow::Continue(())
 }
 
-fn play_game(rng: &mut impl Rng, cfr: &mut CFR) {
-    let possible_starts = Board::possible_starts();
+fn play_game(rng: &mut impl Rng, cfr: &mut Cfr) {
+    let possible_starts = possible_starts(cfr.geometry);
 
     let mut board = possible_starts.choose(rng).unwrap().clone();
-    let mut infoset = board.to_base_infoset();
 
     loop {
         if let Some((outcome, p0, p1)) = board.score() {
             draw_board(&board);
             println!("result: {:?} ({}/{})", outcome, p0, p1);
-            println!("p0 wanted: {:?}", board.p0_wants);
-            println!("p1 wanted: {:?}", board.p1_wants);
+            println!("p0 wanted: {:?}", board.p0_wants());
+            println!("p1 wanted: {:?}", board.p1_wants());
             return
         }
 
-        let next_move = if board.turn % 2 == 0 {
+        let next_move = if board.next_to_move() == CellValue::P0 {
             let possible_moves = board.possible_moves();
             draw_board(&board);
-            println!("What's your move, human? ({:?})", possible_moves);
+            println!("What's your move, human? ({:?})", possible_moves.iter().collect::<Vec<_>>());
             /*
             possible_moves[0]
             */
-            let strategy = cfr.with_infoset_node(
-                infoset, 
-                true, 
-                &cfr.states[board.to_state().0 as usize].unwrap(), 
-                |i| i.get_average_strategy()
-            );
-            let mv = sample_strategy(rng, strategy, &possible_moves);
-            mv
+            let strategy = cfr.with_infoset_node(&board, |i| i.get_average_strategy());
+            sample_strategy(rng, strategy, possible_moves)
         }
         else {
             let possible_moves = board.possible_moves();
             draw_board(&board);
-            println!("What's your move, robot? ({:?})", possible_moves);
-
-            let strategy = cfr.with_infoset_node(
-                infoset, 
-                false, 
-                &cfr.states[board.to_state().0 as usize].unwrap(), 
-                |i| i.get_average_strategy()
-            );
-            let mv = sample_strategy(rng, strategy, &possible_moves);
-            mv
+            println!("What's your move, robot? ({:?})", possible_moves.iter().collect::<Vec<_>>());
+
+            let strategy = cfr.with_infoset_node(&board, |i| i.get_average_strategy());
+            sample_strategy(rng, strategy, possible_moves)
         };
 
         board.play(next_move);
-        infoset = infoset.cons(next_move)
     }
 
 }
@@ -574,15 +969,40 @@ fn draw_board(board: &Board) {
         CellValue::P1 => 'Z',
     };
     // player move
-    println!("{} {} {}\n{} {} {}\n{} {} {}", 
-        nice_cell(board.cells[0]), nice_cell(board.cells[1]), nice_cell(board.cells[2]),
-        nice_cell(board.cells[3]), nice_cell(board.cells[4]), nice_cell(board.cells[5]),
-        nice_cell(board.cells[6]), nice_cell(board.cells[7]), nice_cell(board.cells[8]),
-    );
+    let width = board.geometry().width();
+    for row in 0..board.geometry().height() {
+        let cells: Vec<String> = (0..width).map(|col| nice_cell(board.cell(row * width + col)).to_string()).collect();
+        println!("{}", cells.join(" "));
+    }
 }
 
-fn sample_strategy(rng: &mut impl Rng, strategy: [f32; N_MOVES], possible_moves: &[Move]) -> Move {
-    if possible_moves.len() == 0 { panic!("should never happen"); }
+fn draw_distribution(board: &Board, strategy: [f32; MAX_CELLS]) {
+    draw_board(board);
+    println!("{:?} to move:", board.next_to_move());
+    let width = board.geometry().width();
+    for row in 0..board.geometry().height() {
+        let cells: Vec<String> = (0..width).map(|col| format!("{:5.1}%", strategy[row * width + col] * 100.0)).collect();
+        println!("{}", cells.join(" "));
+    }
+}
+
+// pick a move in proportion to `weights`, without allocating like `sample_strategy` does
+fn sample_move(rng: &mut impl Rng, weights: [f32; MAX_CELLS], moves: MoveSet) -> Move {
+    let total: f32 = moves.iter().map(|m| weights[m.index()]).sum();
+    let mut x = rng.gen::<f32>() * total;
+    let mut last = None;
+    for m in moves {
+        if weights[m.index()] <= 0.0 { continue }
+        x -= weights[m.index()];
+        if x < 0.0 { return m }
+        last = Some(m);
+    }
+    // rounding can leave a sliver at the end
+    last.expect("strategies always have a legal move")
+}
+
+fn sample_strategy(rng: &mut impl Rng, strategy: [f32; MAX_CELLS], possible_moves: MoveSet) -> Move {
+    if possible_moves.is_empty() { panic!("should never happen"); }
 
     // NOTE: This allocates
     let weights = WeightedIndex::new(strategy).unwrap();
@@ -590,19 +1010,192 @@ fn sample_strategy(rng: &mut impl Rng, strategy: [f32; N_MOVES], possible_moves:
     loop {
         let ix = rng.sample(&weights);
         println!("{:?} {}", strategy, ix);
-        if possible_moves.contains(&Move(ix)) {
-            return Move(ix);
+        if possible_moves.contains(Move::new(ix)) {
+            return Move::new(ix);
         } 
 
         // just in case there's somehow some nonzero probability, we loop
     }
 }
 
-impl Infoset {
-    fn cons(&self, m: Move) -> Infoset {
-        let mut is2 = *self;
-        is2.history *= N_MOVES as u32;
-        is2.history += m.0 as u32;
-        is2
+#[test]
+fn test_checkpoint() {
+    let mut cfr = Cfr::new(Geometry::new(3, 2, 3).unwrap());
+    cfr.rule = UpdateRule::DCFR;
+    cfr.train(0.5, 1);
+    let data = encode_checkpoint(&cfr).unwrap();
+    let back = decode_checkpoint(&data).unwrap();
+    assert_eq!(
+        (back.trained_iterations, back.geometry, back.contempt.start, back.rule, back.sampling, back.seed),
+        (1, cfr.geometry, cfr.contempt.start, cfr.rule, cfr.sampling, cfr.seed)
+    );
+    assert_eq!(encode_checkpoint(&back).unwrap(), data);
+
+    // older checkpoints get told apart from broken ones
+    assert!(decode_checkpoint(&bincode::serialize(&cfr).unwrap()).unwrap_err().contains("didn't version"));
+    let mut newer = data.clone();
+    newer[4] = CHECKPOINT_VERSION + 1;
+    assert!(decode_checkpoint(&newer).unwrap_err().contains("only reads version"));
+    assert!(decode_checkpoint(&data[..data.len() - 1]).is_err());
+}
+
+#[test]
+fn test_cli() {
+    let table = "0,0.01,0.1,0.2,0.3,0.33333,0.4,0.5,0.6,0.666666,0.7,0.8,0.9,0.98,0.99,1";
+    assert_eq!(parse_val_expansion(table), Ok(VAL_EXPANSION));
+    assert!(parse_val_expansion("0,0.5,1").unwrap_err().contains("need 16"));
+    for bad in [table.replacen('0', "0.1", 1), table.replace(",1", ",1.5"), table.replace(",0.", ",-0."), ["0"; 16].join(",")] {
+        assert!(parse_val_expansion(&bad).unwrap_err().contains("has to be 0"), "{}", bad);
+    }
+
+    assert_eq!(parse_probability("0.25"), Ok(0.25));
+    for bad in ["-0.1", "1.5", "NaN", "half"] {
+        assert!(parse_probability(bad).is_err(), "{}", bad);
+    }
+
+    let cli = Cli::try_parse_from([
+        "trainer", "train", "--board", "4x4k3", "--iterations", "5", "--save-every", "0", "--contempt-start", "0.25"
+    ]).unwrap();
+    let Command::Train { board, iterations, save_every, contempt_start, contempt_floor, threads, rule, .. } = cli.command else {
+        panic!("not train")
+    };
+    assert_eq!(
+        (board, iterations, save_every, contempt_start, contempt_floor, threads, rule),
+        (Geometry::new(4, 4, 3), 5, 0, Some(0.25), None, 1, None)
+    );
+
+    let cli = Cli::try_parse_from(["trainer", "inspect", "--position", "x../.o./... x o,tie r0 k3"]).unwrap();
+    let Command::Inspect { cfr, strategy, position } = cli.command else { panic!("not inspect") };
+    assert_eq!((cfr, strategy), (PathBuf::from("cfr.dat"), None));
+    assert_eq!(position.unwrap().to_string(), "x../.o./... x o,tie r0 k3");
+
+    assert!(Cli::try_parse_from(["trainer", "train", "--contempt-floor", "2"]).is_err());
+    assert!(Cli::try_parse_from(["trainer", "train", "--board", "4x4"]).is_err());
+    assert!(Cli::try_parse_from(["trainer", "train", "--board", "9x9k3"]).is_err());
+    assert!(Cli::try_parse_from(["trainer", "export", "--val-expansion", "0,1"]).is_err());
+}
+
+// how far a best response gets against the average strategy, on a board small enough to walk
+#[cfg(test)]
+fn small_exploitability(cfr: &Cfr) -> f32 {
+    exploitability_on(cfr.geometry, &AverageStrategy(cfr)).total()
+}
+
+#[test]
+fn test_update_rules() {
+    for text in ["vanilla", "cfr+", "linear", "dcfr:1.5,0,2", "dcfr:1,0.5,3"] {
+        assert_eq!(text.parse::<UpdateRule>().unwrap().to_string(), text);
+    }
+    assert_eq!("dcfr".parse::<UpdateRule>(), Ok(UpdateRule::DCFR));
+    assert!("dcfr:1,2".parse::<UpdateRule>().unwrap_err().contains("not 2 values"));
+    assert!("dcfr:NaN,0,2".parse::<UpdateRule>().unwrap_err().contains("have to be numbers"));
+    assert!("dcfr:1.5,-inf,2".parse::<UpdateRule>().unwrap_err().contains("have to be numbers"));
+    assert!("cfr".parse::<UpdateRule>().unwrap_err().contains("unknown update rule"));
+
+    assert_eq!(UpdateRule::Vanilla.discounts(7), (1.0, 1.0, 1.0));
+    assert_eq!(UpdateRule::CfrPlus.discounts(1), (1.0, 1.0, 0.5));
+    assert_eq!(UpdateRule::CfrPlus.discounts(3), (1.0, 1.0, 0.75));
+    assert_eq!(UpdateRule::Linear.discounts(3), (0.75, 0.75, 0.75));
+    assert_eq!(UpdateRule::DCFR.discounts(1), (0.5, 0.5, 0.25));
+    let (positive, negative, strategy) = UpdateRule::DCFR.discounts(4);
+    assert!((positive - 8.0 / 9.0).abs() < 1e-6 && negative == 0.5 && (strategy - 0.64).abs() < 1e-6);
+
+    // discounting by t/(t+1) after every iteration leaves iteration t weighted in proportion to t
+    let mut node = InfosetNode { legal: [true; MAX_CELLS], regret_sum: [0.0; MAX_CELLS], strategy_sum: [0.0; MAX_CELLS] };
+    for t in 1..=5u64 {
+        node.regret_sum[t as usize] = 1.0;
+        node.regret_sum[10 + t as usize] = -1.0;
+        node.strategy_sum[t as usize] = 1.0;
+        let (positive, negative, strategy) = UpdateRule::Linear.discounts(t);
+        node.discount(positive, negative, strategy);
+    }
+    for t in 1..=5 {
+        assert!((node.regret_sum[t] - t as f32 / 6.0).abs() < 1e-6);
+        assert!((node.regret_sum[10 + t] + t as f32 / 6.0).abs() < 1e-6);
+        assert!((node.strategy_sum[t] - t as f32 / 6.0).abs() < 1e-6);
+    }
+
+    // every rule gets close to an equilibrium on a small board. the untrained strategy is about 0.11 away
+    for rule in [UpdateRule::Vanilla, UpdateRule::CfrPlus, UpdateRule::Linear, UpdateRule::DCFR] {
+        let mut cfr = Cfr::new(Geometry::new(3, 2, 3).unwrap());
+        cfr.rule = rule;
+        let untrained = small_exploitability(&cfr);
+        for _ in 0..30 { cfr.train(0.0, 1); }
+        let trained = small_exploitability(&cfr);
+        println!("{}: {} -> {}", rule, untrained, trained);
+        assert!(trained < 0.005 && trained < untrained / 20.0);
+        if rule.floors_regrets() {
+            assert!(cfr.infosets.values().all(|n| n.regret_sum.iter().all(|r| *r >= 0.0)));
+        }
+    }
+}
+
+#[test]
+fn test_sampling() {
+    for text in ["full", "external", "outcome:0.6", "outcome:0"] {
+        assert_eq!(text.parse::<Sampling>().unwrap().to_string(), text);
+    }
+    assert_eq!("outcome".parse::<Sampling>(), Ok(Sampling::Outcome { exploration: 0.6 }));
+    assert!("outcome:2".parse::<Sampling>().unwrap_err().contains("between 0 and 1"));
+    assert!("chance".parse::<Sampling>().unwrap_err().contains("unknown sampling"));
+
+    // both samplers get there on a small board, just with many more (much cheaper) iterations than full walks
+    for (sampling, iterations, target) in [(Sampling::External, 3000, 0.01), (Sampling::Outcome { exploration: 0.6 }, 30000, 0.03)] {
+        let mut cfr = Cfr::new(Geometry::new(3, 2, 3).unwrap());
+        cfr.sampling = sampling;
+        let untrained = small_exploitability(&cfr);
+        while cfr.trained_iterations < iterations { cfr.train(0.0, 1); }
+        let trained = small_exploitability(&cfr);
+        println!("{}: {} -> {}", sampling, untrained, trained);
+        assert!(trained < target);
+    }
+
+    // a seeded run samples the same games however it's split up between checkpoints
+    let train = |seed, splits: &[u64]| {
+        let mut cfr = Cfr::new(Geometry::new(3, 2, 3).unwrap());
+        cfr.sampling = Sampling::External;
+        cfr.seed = seed;
+        for until in splits {
+            while cfr.trained_iterations < *until { cfr.train(0.1, 1); }
+            cfr = decode_checkpoint(&encode_checkpoint(&cfr).unwrap()).unwrap();
+        }
+        encode_checkpoint(&cfr).unwrap()
+    };
+    assert_eq!(train(7, &[200]), train(7, &[50, 120, 200]));
+    assert_ne!(train(7, &[200]), train(8, &[200]));
+}
+
+#[test]
+fn test_threads_agree() {
+    // every thread plays the same starting strategies, so splitting the deals up differently only changes
+    // the order things get added in. that includes CFR+, as long as the floor goes on after all of them
+    let train = |threads| {
+        let mut cfr = Cfr::new(Geometry::new(3, 2, 3).unwrap());
+        cfr.rule = UpdateRule::CfrPlus;
+        for _ in 0..5 { cfr.train(0.0, threads); }
+        cfr.infosets
+    };
+    let (two, three) = (train(2), train(3));
+    assert_eq!(two.len(), three.len());
+    for (key, node) in two.iter() {
+        let other = &three[key];
+        for m in 0..MAX_CELLS {
+            assert!((node.regret_sum[m] - other.regret_sum[m]).abs() < 1e-4);
+            assert!((node.strategy_sum[m] - other.strategy_sum[m]).abs() < 1e-4);
+        }
+    }
+}
+
+#[test]
+fn test_threads_converge() {
+    // one thread updates as it goes and more play the iteration's starting strategies, so they take
+    // different paths, but both get to an equilibrium. asking for more threads than deals is fine
+    for threads in [1, 4, 20] {
+        let mut cfr = Cfr::new(Geometry::new(3, 2, 3).unwrap());
+        cfr.rule = UpdateRule::DCFR;
+        for _ in 0..30 { cfr.train(0.0, threads); }
+        let trained = small_exploitability(&cfr);
+        println!("{} threads: {}", threads, trained);
+        assert!(trained < 0.001);
     }
 }
@@ -0,0 +1,78 @@
+use crate::{consts::N_WINCONS, game::{Board, CellValue, Outcome}, policy::Policy};
+
+// Chance that a player ignores their strategy and plays uniformly at random.
+// Stops one move the table thinks is impossible from ruling a wincon out for good.
+const TREMBLE: f32 = 0.01;
+
+/// What one player probably wants, given only the moves they've made so far.
+///
+/// Starts from a uniform prior over `Outcome::ALL` (that's how wincons are dealt), and for each of
+/// `seat`'s moves, multiplies each wincon's weight by the chance `policy` gives that move when
+/// the player wants that wincon. The result is indexed like `Outcome::ALL`.
+///
+/// `policy` is our model of how the player chooses, so it should be whatever they're being advised
+/// to play: for the front end that's the trained strategy with its `TakeWins` and `SymmetricOpening` wrappers.
+pub fn wincon_belief(policy: &impl Policy, board: &Board, seat: CellValue) -> [f32; N_WINCONS] {
+    let as_p0 = seat == CellValue::P0;
+    let moves = board.moves();
+
+    let mut belief = [1.0 / N_WINCONS as f32; N_WINCONS];
+    for (wants, weight) in Outcome::ALL.iter().zip(belief.iter_mut()) {
+        // replay the game as if `seat` wanted `wants`. their strategy can't see the other wincon, so it doesn't matter
+        let (p0_wants, p1_wants) = if as_p0 { (*wants, Outcome::Tie) } else { (Outcome::Tie, *wants) };
+        let mut hypothetical = Board::with_geometry(board.geometry(), p0_wants, p1_wants);
+        for m in moves.iter() {
+            if hypothetical.next_to_move() == seat {
+                let n_possible_moves = hypothetical.possible_moves().len();
+                let dist = policy.distribution(&hypothetical);
+                *weight *= (1.0 - TREMBLE) * dist[m.0] + TREMBLE / n_possible_moves as f32;
+            }
+            hypothetical.play(*m);
+        }
+    }
+
+    let total: f32 = belief.iter().sum();
+    belief.map(|b| b / total)
+}
+
+#[cfg(feature = "embedded-strategy")]
+#[test]
+fn test_wincon_belief() {
+    use crate::{policy::{SymmetricOpening, TakeWins}, strategy::Strategy};
+
+    let strategy = Strategy::from_legacy_bytes(include_bytes!("strategy.dat")).unwrap();
+
+    // no moves, no information
+    let board = Board::new(Outcome::P0Win, Outcome::Tie);
+    let belief = wincon_belief(&strategy, &board, CellValue::P0);
+    assert!(belief.iter().all(|b| (b - 1.0 / 3.0).abs() < 1e-6));
+
+    // let p0 play the policy's favourite moves for a while, then see if we can tell what it wants.
+    // the front end models players with the wrapped strategy, so check that as well as the bare table
+    fn check(policy: &impl Policy) {
+        for wants in Outcome::ALL {
+            let mut board = Board::new(wants, Outcome::Tie);
+            for _ in 0..3 {
+                let dist = policy.distribution(&board);
+                let best = board.possible_moves().iter().max_by(|a, b| dist[a.0].partial_cmp(&dist[b.0]).unwrap()).unwrap();
+                board.play(best);
+                if board.score().is_some() { break }
+                let p1_move = board.possible_moves().iter().next().unwrap();
+                board.play(p1_move);
+                if board.score().is_some() { break }
+            }
+
+            let belief = wincon_belief(policy, &board, CellValue::P0);
+            println!("{:?} -> {:?}", wants, belief);
+            assert!((belief.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+            let guess = (0..N_WINCONS).max_by(|a, b| belief[*a].partial_cmp(&belief[*b]).unwrap()).unwrap();
+            assert_eq!(Outcome::ALL[guess], wants);
+
+            // p1 didn't play by its strategy, but that shouldn't break anything
+            let belief_p1 = wincon_belief(policy, &board, CellValue::P1);
+            assert!((belief_p1.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        }
+    }
+    check(&strategy);
+    check(&TakeWins(SymmetricOpening(&strategy)));
+}
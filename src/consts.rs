@@ -1 +1,10 @@
-pub const N_MOVES: usize = 9;
\ No newline at end of file
+// the standard game, which the web build plays and strategy files default to
+pub const BOARD_WIDTH: usize = 3;
+pub const BOARD_HEIGHT: usize = 3;
+pub const LINE_LENGTH: usize = 3;
+
+// the biggest board we support. arrays of cells are this long, whatever the board
+pub const MAX_CELLS: usize = 25;
+
+// Tie, P0Win, P1Win
+pub const N_WINCONS: usize = 3;
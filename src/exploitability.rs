@@ -0,0 +1,130 @@
+use crate::{consts::{MAX_CELLS, N_WINCONS}, game::{Board, CellValue, Outcome}, geometry::Geometry, policy::Policy};
+
+/// How much a best response can win against a policy playing both seats.
+///
+/// Utilities are the trainer's zero-sum ones: (my score - your score), without contempt.
+/// Wincons are dealt uniformly at random, as in the real game.
+#[derive(Clone, Copy, Debug)]
+pub struct Exploitability {
+    /// What a best response in each seat expects to earn against the policy in the other.
+    pub best_response: [f32; 2],
+    /// The same, split up by deal: `per_wincon[p0_wants][p1_wants][seat]`, with wincons indexed like `Outcome::ALL`.
+    /// The best response still can't see the opponent's wincon, so it's one strategy across each row (or column).
+    pub per_wincon: [[[f32; 2]; N_WINCONS]; N_WINCONS],
+}
+
+impl Exploitability {
+    /// Half the sum of the best responses. Zero for an equilibrium, positive for anything else.
+    pub fn total(&self) -> f32 {
+        (self.best_response[0] + self.best_response[1]) / 2.0
+    }
+
+    /// `total`, but for a single deal. Not necessarily positive on its own.
+    pub fn for_wincons(&self, p0_wants: Outcome, p1_wants: Outcome) -> f32 {
+        let [br0, br1] = self.per_wincon[wincon_index(p0_wants)][wincon_index(p1_wants)];
+        (br0 + br1) / 2.0
+    }
+}
+
+/// Walk the whole game tree of the standard game, computing a best response to `policy` for each seat.
+pub fn exploitability(policy: &impl Policy) -> Exploitability {
+    exploitability_on(Geometry::STANDARD, policy)
+}
+
+/// `exploitability` for a game on another board. The walk isn't memoized, so keep it to 9 cells or so.
+pub fn exploitability_on(geometry: Geometry, policy: &impl Policy) -> Exploitability {
+    let mut best_response = [0.0; 2];
+    let mut per_wincon = [[[0.0; 2]; N_WINCONS]; N_WINCONS];
+    let prior = 1.0 / N_WINCONS as f32;
+
+    for (seat_ix, seat) in [CellValue::P0, CellValue::P1].iter().enumerate() {
+        for (mine_ix, mine) in Outcome::ALL.iter().enumerate() {
+            // one board per wincon the opponent might have. they all see the same moves
+            let boards = Outcome::ALL.map(|theirs| 
+                if *seat == CellValue::P0 { Board::with_geometry(geometry, *mine, theirs) } else { Board::with_geometry(geometry, theirs, *mine) }
+            );
+            let values = best_response_values(policy, *seat, &boards, [prior; N_WINCONS]);
+
+            for (theirs_ix, value) in values.iter().enumerate() {
+                let (p0_ix, p1_ix) = if *seat == CellValue::P0 { (mine_ix, theirs_ix) } else { (theirs_ix, mine_ix) };
+                per_wincon[p0_ix][p1_ix][seat_ix] = value / prior;
+                best_response[seat_ix] += value * prior;
+            }
+        }
+    }
+
+    Exploitability { best_response, per_wincon }
+}
+
+// `boards[o]` is the game where the opponent wants `Outcome::ALL[o]`, reached with (chance * opponent) probability `reach[o]`.
+// Returns the best responder's utility in each of them, weighted by `reach`.
+fn best_response_values(policy: &impl Policy, seat: CellValue, boards: &[Board; N_WINCONS], reach: [f32; N_WINCONS]) -> [f32; N_WINCONS] {
+    if boards[0].score().is_some() {
+        let mut values = [0.0; N_WINCONS];
+        for o in 0..N_WINCONS {
+            let (_, p0, p1) = boards[o].score().unwrap();
+            let util_p0 = (p0 - p1) as f32;
+            values[o] = reach[o] * if seat == CellValue::P0 { util_p0 } else { -util_p0 };
+        }
+        return values
+    }
+
+    let moves = boards[0].possible_moves();
+    let child = |m| boards.clone().map(|mut b| { b.play(m); b });
+
+    if boards[0].next_to_move() == seat {
+        // the best responder doesn't know which board it's on, so it has to pick one move for all of them
+        moves.iter()
+            .map(|m| best_response_values(policy, seat, &child(m), reach))
+            .max_by(|a, b| a.iter().sum::<f32>().partial_cmp(&b.iter().sum::<f32>()).unwrap())
+            .unwrap()
+    } else {
+        let dists: Vec<[f32; MAX_CELLS]> = boards.iter().map(|b| policy.distribution(b)).collect();
+        let mut values = [0.0; N_WINCONS];
+        for m in moves {
+            let mut child_reach = reach;
+            for o in 0..N_WINCONS { child_reach[o] *= dists[o][m.0] }
+            if child_reach.iter().all(|r| *r == 0.0) { continue }
+
+            let child_values = best_response_values(policy, seat, &child(m), child_reach);
+            for o in 0..N_WINCONS { values[o] += child_values[o] }
+        }
+        values
+    }
+}
+
+fn wincon_index(o: Outcome) -> usize {
+    Outcome::ALL.iter().position(|x| *x == o).unwrap()
+}
+
+#[test]
+fn test_exploitability() {
+    use crate::policy::{Minimax, Uniform};
+
+    // playing at random is very beatable from both seats
+    let uniform = exploitability(&Uniform);
+    println!("{:?}", uniform);
+    assert!(uniform.best_response[0] > 0.0 && uniform.best_response[1] > 0.0);
+
+    // the deals are equally likely, so each seat's best response is the average over them
+    for seat in 0..2 {
+        let average = uniform.per_wincon.iter().flatten().map(|v| v[seat]).sum::<f32>() / (N_WINCONS * N_WINCONS) as f32;
+        assert!((average - uniform.best_response[seat]).abs() < 1e-5);
+    }
+
+    // and on smaller boards. minimax searches all of 3x2 and plays an equilibrium, so nothing exploits it
+    let small_board = Geometry::new(3, 2, 3).unwrap();
+    let small = exploitability_on(small_board, &Uniform);
+    assert!(small.best_response[0] > 0.0 && small.best_response[1] > 0.0);
+    assert!(exploitability_on(small_board, &Minimax).total().abs() < 1e-5);
+
+    // the trained strategy had better do better than that
+    #[cfg(feature = "embedded-strategy")]
+    {
+        let strategy = crate::strategy::Strategy::from_legacy_bytes(include_bytes!("strategy.dat")).unwrap();
+        let trained = exploitability(&strategy);
+        println!("{:?}", trained);
+        assert!(trained.total() >= 0.0);
+        assert!(trained.total() < uniform.total());
+    }
+}
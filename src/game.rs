@@ -1,119 +1,433 @@
+use std::{fmt, iter::FromIterator};
+
 use wasm_bindgen::prelude::*;
-use crate::{consts::N_MOVES, rotation::Rotation};
+use crate::{consts::MAX_CELLS, geometry::{Geometry, Layout}, rotation::{Rotation, Symmetry}};
 
 
 #[wasm_bindgen]
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(into = "crate::serialize::BoardRepr", try_from = "crate::serialize::BoardRepr"))]
 pub struct Board {
     pub(crate) rotation: Rotation,  // only used with JS FFI
-    pub(crate) cells: [CellValue; N_MOVES],
+    pub(crate) layout: &'static Layout,
+    pub(crate) masks: [u32; 2],  // the cells each player has taken, as bits. p0 first
     pub(crate) p0_wants: Outcome,
     pub(crate) p1_wants: Outcome,
     pub(crate) turn: u8,
     pub(crate) infoset: Infoset,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum CellValue { Empty, P0, P1 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Outcome { Tie, P0Win, P1Win }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Move(pub(crate) usize);
 
+impl Move {
+    /// A move on the cell at `index`, counting row by row from the top left.
+    pub fn new(index: usize) -> Move {
+        Move(index)
+    }
+
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// Why a move can't be played.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IllegalMove {
+    /// The cell isn't on the board.
+    OutOfRange { index: usize },
+    /// Someone's already played there.
+    Occupied { index: usize },
+    /// Someone has already won, or the board is full.
+    GameOver,
+    /// The first move has to be one of the geometry's first moves: the front end turns the board to make it one.
+    NotCanonicalOnFirstTurn { index: usize },
+}
+
+impl fmt::Display for IllegalMove {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IllegalMove::OutOfRange { index } => write!(f, "cell {} is off the board", index),
+            IllegalMove::Occupied { index } => write!(f, "cell {} is already taken", index),
+            IllegalMove::GameOver => write!(f, "the game is over"),
+            IllegalMove::NotCanonicalOnFirstTurn { index } => 
+                write!(f, "cell {} isn't a first move; turn the board so it is one", index),
+        }
+    }
+}
+
+impl std::error::Error for IllegalMove {}
+
+/// A set of cells, kept as a bitmask. Iterates in cell order.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MoveSet(u32);
+
+impl MoveSet {
+    pub const EMPTY: MoveSet = MoveSet(0);
+
+    pub fn from_mask(mask: u32) -> MoveSet {
+        MoveSet(mask)
+    }
+
+    pub fn mask(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, m: Move) -> bool {
+        m.0 < 32 && self.0 & 1 << m.0 != 0
+    }
+
+    pub fn insert(&mut self, m: Move) {
+        assert!(m.0 < 32, "move {} doesn't fit in a MoveSet", m.0);
+        self.0 |= 1 << m.0
+    }
+
+    pub fn len(self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn iter(self) -> MoveSetIter {
+        MoveSetIter(self.0)
+    }
+}
+
+impl IntoIterator for MoveSet {
+    type Item = Move;
+    type IntoIter = MoveSetIter;
+
+    fn into_iter(self) -> MoveSetIter {
+        self.iter()
+    }
+}
+
+impl FromIterator<Move> for MoveSet {
+    fn from_iter<I: IntoIterator<Item = Move>>(iter: I) -> MoveSet {
+        let mut set = MoveSet::EMPTY;
+        for m in iter { set.insert(m) }
+        set
+    }
+}
+
+/// The moves in a `MoveSet`, lowest cell first.
+pub struct MoveSetIter(u32);
+
+impl Iterator for MoveSetIter {
+    type Item = Move;
+
+    fn next(&mut self) -> Option<Move> {
+        if self.0 == 0 { return None }
+        let i = self.0.trailing_zeros();
+        self.0 &= self.0 - 1;
+        Some(Move(i as usize))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.0.count_ones() as usize;
+        (n, Some(n))
+    }
+}
+
+impl ExactSizeIterator for MoveSetIter {}
+
 
 // infosets
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct Infoset { 
     p0_private: u32, 
     p1_private: u32,
-    history: u32
+    pub(crate) history: InfosetKey,
 }
 
 impl Infoset {
-    pub fn to_key(&self, as_p0: bool) -> (u32, u32) {
+    pub fn to_key(self, as_p0: bool) -> (InfosetKey, u32) {
         (self.history, if as_p0 { self.p0_private } else { self.p1_private })
     }
 }
 
+/// The moves of a game so far, packed as digits in base `n_cells` after a leading 1.
+///
+/// Keys for games of the same length sort like their move sequences, which is what makes
+/// `canonical_history` and the strategy file's ordering work. A u128 holds a full game on any
+/// board up to `MAX_CELLS` cells (25^26 < 2^121), but pushing still checks, in case that changes.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct InfosetKey(u128);
+
+impl InfosetKey {
+    /// The key of a game where nobody has moved yet.
+    pub const EMPTY: InfosetKey = InfosetKey(1);
+
+    /// A key from its packed form, as stored in strategy files.
+    /// Keys that weren't built from moves don't mean anything, but they still sort.
+    pub fn from_raw(raw: u128) -> InfosetKey {
+        InfosetKey(raw)
+    }
+
+    pub fn raw(self) -> u128 {
+        self.0
+    }
+
+    /// The key after one more move, or `None` if it doesn't fit.
+    pub fn push(self, geometry: Geometry, m: Move) -> Option<InfosetKey> {
+        let base = geometry.n_cells() as u128;
+        self.0.checked_mul(base)?.checked_add(m.0 as u128).map(InfosetKey)
+    }
+
+    /// The key before the last move, and the last move. `None` for `EMPTY`.
+    pub fn pop(self, geometry: Geometry) -> Option<(InfosetKey, Move)> {
+        if self.0 <= 1 { return None }
+        let base = geometry.n_cells() as u128;
+        Some((InfosetKey(self.0 / base), Move((self.0 % base) as usize)))
+    }
+
+    pub fn from_moves(geometry: Geometry, moves: &[Move]) -> Option<InfosetKey> {
+        moves.iter().try_fold(InfosetKey::EMPTY, |key, m| key.push(geometry, *m))
+    }
+
+    /// The moves packed into the key, oldest first.
+    pub fn moves(self, geometry: Geometry) -> Vec<Move> {
+        let base = geometry.n_cells() as u128;
+        let mut history = self.0;
+        let mut moves = vec![];
+        while history > 1 {
+            moves.push(Move((history % base) as usize));
+            history /= base;
+        }
+        moves.reverse();
+        moves
+    }
+}
+
+/// Pick the orientation of a packed history that packs to the smallest number, returning the
+/// symmetry that takes the history there and the canonical history itself.
+///
+/// All the histories are the same length, so this is the same as comparing the moves in order,
+/// and first moves end up in the geometry's first moves like `Board::possible_moves` wants.
+pub(crate) fn canonical_history(geometry: Geometry, history: InfosetKey) -> (Symmetry, InfosetKey) {
+    let moves = history.moves(geometry);
+    let transformed = |s: Symmetry| {
+        let moved: Vec<Move> = moves.iter().map(|m| Move(s.transform_index(geometry, m.0 as u8) as usize)).collect();
+        InfosetKey::from_moves(geometry, &moved).expect("same length as a key that fit")
+    };
+
+    let symmetry = Symmetry::canonical_by_key(geometry, transformed);
+    (symmetry, transformed(symmetry))
+}
+
 impl Board {
+    /// A game on the standard 3x3 board.
     pub fn new(p0_wants: Outcome, p1_wants: Outcome) -> Self {
+        Board::with_geometry(Geometry::STANDARD, p0_wants, p1_wants)
+    }
+
+    pub fn with_geometry(geometry: Geometry, p0_wants: Outcome, p1_wants: Outcome) -> Self {
         Self {
             rotation: Rotation::Straight,
-            cells: [CellValue::Empty; 9],
+            layout: geometry.layout(),
+            masks: [0, 0],
             p0_wants, p1_wants,
             turn: 0,
             infoset: Infoset {
-                history: 1,
+                history: InfosetKey::EMPTY,
                 p0_private: p0_wants.to_smallint(),
                 p1_private: p1_wants.to_smallint(),
             }
         }
     }
 
-    pub fn possible_moves(&self) -> Vec<Move> {
-        if self.turn >= N_MOVES as u8 { return vec![] }
-        if self.turn == 0 { return vec![Move(0), Move(1), Move(4)]; };
+    pub fn geometry(&self) -> Geometry {
+        self.layout.geometry
+    }
+
+    pub fn n_cells(&self) -> usize {
+        self.layout.geometry.n_cells()
+    }
+
+    /// How many moves have been played.
+    pub fn turn(&self) -> usize {
+        self.turn as usize
+    }
+
+    pub fn p0_wants(&self) -> Outcome {
+        self.p0_wants
+    }
+
+    pub fn p1_wants(&self) -> Outcome {
+        self.p1_wants
+    }
+
+    /// The (history, private) key a strategy file would use for `seat`'s view of the game.
+    pub fn infoset_key(&self, seat: CellValue) -> (InfosetKey, u32) {
+        self.infoset.to_key(seat == CellValue::P0)
+    }
+
+    pub fn possible_moves(&self) -> MoveSet {
+        MoveSet(self.legal_mask())
+    }
+
+    /// `possible_moves` as a bare mask of cells.
+    pub fn legal_mask(&self) -> u32 {
+        // symmetries on the board
+        if self.turn == 0 { return self.layout.first_moves.mask() }
+        self.empty_mask()
+    }
+
+    fn empty_mask(&self) -> u32 {
+        self.layout.all_cells & !(self.masks[0] | self.masks[1])
+    }
 
-        let mut out = vec![];
-        for (i, cell) in self.cells.iter().enumerate() {
-            // symmetries on the board
+    /// Who's in the cell at `i`. Cells off the board are empty.
+    pub fn cell(&self, i: usize) -> CellValue {
+        if i >= self.n_cells() { CellValue::Empty }
+        else if self.masks[0] & 1 << i != 0 { CellValue::P0 }
+        else if self.masks[1] & 1 << i != 0 { CellValue::P1 }
+        else { CellValue::Empty }
+    }
+
+    // every cell, for the matrix-shaped code. only the first `n_cells` are on the board
+    pub(crate) fn cells(&self) -> [CellValue; MAX_CELLS] {
+        std::array::from_fn(|i| self.cell(i))
+    }
 
-            if let CellValue::Empty = cell { 
-                out.push(Move(i))
+    /// The legal moves that would complete a line for `player`.
+    pub fn winning_moves(&self, player: CellValue) -> MoveSet {
+        let (mine, theirs) = match player {
+            CellValue::P0 => (self.masks[0], self.masks[1]),
+            CellValue::P1 => (self.masks[1], self.masks[0]),
+            CellValue::Empty => return MoveSet::EMPTY,
+        };
+        let mut wins = 0;
+        for line in self.layout.lines.iter() {
+            if line & theirs == 0 && (line & mine).count_ones() + 1 == line.count_ones() {
+                wins |= line & !mine;
             }
         }
-        return out;
+        MoveSet(wins & self.legal_mask())
+    }
+
+    /// Whether someone has won or the board is full.
+    pub fn is_terminal(&self) -> bool {
+        self.empty_mask() == 0 || self.winner().is_some()
+    }
+
+    fn winner(&self) -> Option<Outcome> {
+        if self.has_line(CellValue::P0) { Some(Outcome::P0Win) }
+        else if self.has_line(CellValue::P1) { Some(Outcome::P1Win) }
+        else { None }
+    }
+
+    // whether `player` has completed a line
+    pub(crate) fn has_line(&self, player: CellValue) -> bool {
+        let mask = match player {
+            CellValue::P0 => self.masks[0],
+            CellValue::P1 => self.masks[1],
+            CellValue::Empty => return false,
+        };
+        self.layout.lines.iter().any(|line| mask & line == *line)
+    }
+
+    /// The symmetry that takes this position to its canonical orientation: the one whose cells
+    /// come first in (Empty, P0, P1) order.
+    pub fn canonical_symmetry(&self) -> Symmetry {
+        let cells = self.cells();
+        Symmetry::canonical_by_key(self.geometry(), |s| s.transform_matrix(self.geometry(), cells))
     }
 
     pub fn next_to_move(&self) -> CellValue {
-        if self.turn % 2 == 0 { CellValue::P0 } else { CellValue::P1 }
+        if self.turn.is_multiple_of(2) { CellValue::P0 } else { CellValue::P1 }
     }
 
+    /// Play a move, panicking if it isn't legal. See `try_play`.
     pub fn play(&mut self, m: Move) {
-        assert!(m.0 < self.cells.len() && self.cells[m.0] == CellValue::Empty);
-        self.cells[m.0] = self.next_to_move();
+        if let Err(e) = self.try_play(m) { panic!("{}", e) }
+    }
+
+    /// Play a move, if it's one of `possible_moves`.
+    pub fn try_play(&mut self, m: Move) -> Result<(), IllegalMove> {
+        if m.0 >= self.n_cells() { return Err(IllegalMove::OutOfRange { index: m.0 }) }
+        if self.is_terminal() { return Err(IllegalMove::GameOver) }
+        if self.empty_mask() & 1 << m.0 == 0 { return Err(IllegalMove::Occupied { index: m.0 }) }
+        if !self.possible_moves().contains(m) { return Err(IllegalMove::NotCanonicalOnFirstTurn { index: m.0 }) }
+
+        self.masks[self.turn as usize % 2] |= 1 << m.0;
         self.turn += 1;
 
-        self.infoset.history *= N_MOVES as u32;
-        self.infoset.history += m.0 as u32;
+        self.infoset.history = self.infoset.history.push(self.geometry(), m)
+            .expect("every game on a supported board fits in a key");
+        Ok(())
+    }
+
+    /// Take back the last move, returning it. Does nothing on an empty board.
+    pub fn undo(&mut self) -> Option<Move> {
+        let (history, m) = self.infoset.history.pop(self.geometry())?;
+        self.infoset.history = history;
+        self.turn -= 1;
+        self.masks[self.turn as usize % 2] &= !(1 << m.0);
+
+        // the front end picks a rotation with the first move, so it has to be able to pick again
+        if self.turn == 0 { self.rotation = Rotation::Straight }
+        Some(m)
+    }
+
+    /// The moves made so far, oldest first, in engine orientation.
+    pub fn moves(&self) -> Vec<Move> {
+        self.infoset.history.moves(self.geometry())
+    }
+
+    /// `moves`, but turned around to where the front end shows them.
+    pub fn display_moves(&self) -> Vec<Move> {
+        self.moves().into_iter()
+            .map(|m| Move(self.rotation.rotate_index(self.geometry(), m.0 as u8) as usize))
+            .collect()
     }
 
     pub fn score(&self) -> Option<(Outcome, i8, i8)> {
-        let all_eq = |a, b, c, d| a == b && b == c && c == d;
-
-        let mut outcome = None;
-        for (p, possible_outcome) in [(CellValue::P0, Outcome::P0Win), (CellValue::P1, Outcome::P1Win)] {
-            if 
-                all_eq(p, self.cells[0], self.cells[1], self.cells[2]) || 
-                all_eq(p, self.cells[3], self.cells[4], self.cells[5]) || 
-                all_eq(p, self.cells[6], self.cells[7], self.cells[8]) || 
-
-                all_eq(p, self.cells[0], self.cells[3], self.cells[6]) || 
-                all_eq(p, self.cells[1], self.cells[4], self.cells[7]) || 
-                all_eq(p, self.cells[2], self.cells[5], self.cells[8]) || 
-
-                all_eq(p, self.cells[0], self.cells[4], self.cells[8]) || 
-                all_eq(p, self.cells[2], self.cells[4], self.cells[6])
-            {
-                outcome = Some(possible_outcome);
-                break;
-            }
-        }
+        let mut outcome = self.winner();
 
-        if self.turn >= N_MOVES as u8 && outcome == None {
+        if self.empty_mask() == 0 && outcome.is_none() {
             outcome = Some(Outcome::Tie);
         }
 
         if let Some(o) = outcome {
             return Some((o, (self.p0_wants == o) as i8, (self.p1_wants == o) as i8))
         }
-        return None
+        None
+    }
+}
+
+// every board on a geometry shares a layout, so name the geometry instead of printing all its lines
+impl fmt::Debug for Board {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Board")
+            .field("geometry", &self.geometry())
+            .field("masks", &self.masks)
+            .field("p0_wants", &self.p0_wants)
+            .field("p1_wants", &self.p1_wants)
+            .field("turn", &self.turn)
+            .field("rotation", &self.rotation)
+            .field("infoset", &self.infoset)
+            .finish()
     }
 }
 
 impl Outcome {
+    /// In the same order as `to_smallint`.
+    pub const ALL: [Outcome; 3] = [Outcome::Tie, Outcome::P0Win, Outcome::P1Win];
+
     fn to_smallint(self) -> u32 {
         match self {
             Outcome::Tie => 0,
@@ -121,4 +435,112 @@ impl Outcome {
             Outcome::P1Win => 2,
         }
     }
-}
\ No newline at end of file
+}
+#[test]
+fn test_score() {
+    // the hand-written lines this used to have
+    let mut board = Board::new(Outcome::P0Win, Outcome::Tie);
+    for m in [4, 0, 2, 8, 6] { board.play(Move(m)) }
+    assert_eq!(board.score(), Some((Outcome::P0Win, 1, 0)));
+
+    // 4x4, three in a row, on a diagonal that doesn't touch the corners
+    let geometry = Geometry::new(4, 4, 3).unwrap();
+    let mut board = Board::with_geometry(geometry, Outcome::Tie, Outcome::P1Win);
+    for m in [0, 1, 3, 6, 12, 11] {
+        assert_eq!(board.score(), None);
+        board.play(Move(m));
+    }
+    assert_eq!(board.score(), Some((Outcome::P1Win, 0, 1)));
+}
+
+#[test]
+fn test_infoset_key() {
+    // a whole game on the biggest board still fits
+    let geometry = Geometry::new(5, 5, 4).unwrap();
+    let moves: Vec<Move> = (0..25).rev().map(Move).collect();
+    let key = InfosetKey::from_moves(geometry, &moves).unwrap();
+    assert_eq!(key.moves(geometry), moves);
+
+    // keys of the same length sort like their moves
+    let g = Geometry::STANDARD;
+    assert!(InfosetKey::from_moves(g, &[Move(0), Move(8)]) < InfosetKey::from_moves(g, &[Move(1), Move(0)]));
+
+    assert_eq!(InfosetKey::from_raw(u128::MAX).push(g, Move(0)), None);
+}
+
+#[test]
+fn test_bitboard() {
+    let mut board = Board::new(Outcome::P0Win, Outcome::P1Win);
+    assert_eq!(board.legal_mask(), 0b000010011);
+
+    // p0 has 0 and 1, p1 has 4 and 8
+    for m in [0, 4, 1, 8] { board.play(Move(m)) }
+    assert_eq!(board.legal_mask(), 0b011101100);
+    assert_eq!(board.winning_moves(CellValue::P0), MoveSet::from_iter([Move(2)]));
+    assert_eq!(board.winning_moves(CellValue::P1), MoveSet::EMPTY);
+    assert_eq!(board.possible_moves().iter().collect::<Vec<_>>(), [2, 3, 5, 6, 7].map(Move));
+    assert_eq!(board.possible_moves().len(), 5);
+    assert!(board.possible_moves().contains(Move(7)) && !board.possible_moves().contains(Move(8)));
+    assert_eq!(board.cell(4), CellValue::P1);
+    assert_eq!(board.cell(9), CellValue::Empty);
+    assert_eq!(board.cell(40), CellValue::Empty);
+    assert!(!board.is_terminal());
+
+    board.play(Move(2));
+    assert!(board.is_terminal());
+    assert_eq!(board.score(), Some((Outcome::P0Win, 1, 0)));
+
+    // a full board with no lines is terminal too
+    let mut board = Board::new(Outcome::Tie, Outcome::Tie);
+    for m in [0, 4, 8, 1, 7, 6, 2, 5, 3] { board.play(Move(m)) }
+    assert!(board.is_terminal());
+    assert_eq!(board.legal_mask(), 0);
+    assert_eq!(board.score(), Some((Outcome::Tie, 1, 1)));
+}
+
+#[test]
+#[should_panic(expected = "move 32 doesn't fit in a MoveSet")]
+fn test_move_set_overflow() {
+    let mut moves = MoveSet::EMPTY;
+    moves.insert(Move(32));
+}
+
+#[test]
+fn test_undo() {
+    let mut board = Board::new(Outcome::P0Win, Outcome::P1Win);
+    assert_eq!(board.undo(), None);
+
+    let moves = [4, 0, 2, 8].map(Move);
+    for m in moves { board.play(m) }
+    assert_eq!(board.moves(), moves);
+
+    let before = board.clone();
+    board.play(Move(6));
+    assert_eq!(board.score(), Some((Outcome::P0Win, 1, 0)));
+    assert_eq!(board.undo(), Some(Move(6)));
+    assert_eq!(board.score(), None);
+    assert_eq!((board.masks, board.turn, board.infoset), (before.masks, before.turn, before.infoset));
+
+    // the front end sees the board turned around
+    board.rotation = Rotation::Right;
+    assert_eq!(board.display_moves(), [4, 6, 0, 2].map(Move));
+
+    while board.undo().is_some() {}
+    assert_eq!(board.turn, 0);
+    assert_eq!(board.rotation, Rotation::Straight);
+    assert_eq!(board.legal_mask(), board.layout.first_moves.mask());
+}
+
+#[test]
+fn test_try_play() {
+    let mut board = Board::new(Outcome::P0Win, Outcome::P1Win);
+    assert_eq!(board.try_play(Move(9)), Err(IllegalMove::OutOfRange { index: 9 }));
+    assert_eq!(board.try_play(Move(8)), Err(IllegalMove::NotCanonicalOnFirstTurn { index: 8 }));
+    assert_eq!(board.try_play(Move(4)), Ok(()));
+    assert_eq!(board.try_play(Move(4)), Err(IllegalMove::Occupied { index: 4 }));
+
+    for m in [0, 2, 8, 6] { board.try_play(Move(m)).unwrap() }
+    assert_eq!(board.try_play(Move(1)), Err(IllegalMove::GameOver));
+    // failed moves don't touch the board
+    assert_eq!(board.moves(), [4, 0, 2, 8, 6].map(Move));
+}
@@ -0,0 +1,115 @@
+use std::sync::Mutex;
+
+use crate::{consts::{BOARD_WIDTH, BOARD_HEIGHT, LINE_LENGTH, MAX_CELLS}, game::{Move, MoveSet}, rotation::{Rotation, Symmetry}};
+
+/// The shape of an m,n,k game: the board's width and height, and how many in a row wins.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(try_from = "crate::serialize::GeometryRepr"))]
+pub struct Geometry {
+    width: u8,
+    height: u8,
+    line_length: u8,
+}
+
+/// Everything about a geometry that's worth working out once: win lines, symmetries and so on.
+/// Get one with `Geometry::layout`.
+#[derive(Debug)]
+pub struct Layout {
+    pub(crate) geometry: Geometry,
+    pub(crate) lines: Vec<u32>,  // a mask of the cells in each win line
+    pub(crate) all_cells: u32,  // a mask of every cell on the board
+    pub(crate) rotations: Vec<Rotation>,
+    pub(crate) symmetries: Vec<Symmetry>,
+    pub(crate) first_moves: MoveSet,
+}
+
+// layouts are leaked on first use: there are only ever a handful of geometries
+static LAYOUTS: Mutex<Vec<&'static Layout>> = Mutex::new(Vec::new());
+
+impl Geometry {
+    pub const STANDARD: Geometry = Geometry { 
+        width: BOARD_WIDTH as u8, 
+        height: BOARD_HEIGHT as u8, 
+        line_length: LINE_LENGTH as u8 
+    };
+
+    /// None if the board is empty, too big, or the lines can't fit on it.
+    pub fn new(width: usize, height: usize, line_length: usize) -> Option<Geometry> {
+        if width == 0 || height == 0 || width * height > MAX_CELLS { return None }
+        if line_length == 0 || line_length > width.max(height) { return None }
+        Some(Geometry { width: width as u8, height: height as u8, line_length: line_length as u8 })
+    }
+
+    pub fn width(self) -> usize { self.width as usize }
+    pub fn height(self) -> usize { self.height as usize }
+    pub fn line_length(self) -> usize { self.line_length as usize }
+    pub fn n_cells(self) -> usize { self.width() * self.height() }
+    pub fn is_square(self) -> bool { self.width == self.height }
+
+    pub(crate) fn layout(self) -> &'static Layout {
+        let mut layouts = LAYOUTS.lock().unwrap();
+        if let Some(layout) = layouts.iter().find(|l| l.geometry == self) {
+            return layout
+        }
+        let layout: &'static Layout = Box::leak(Box::new(Layout::new(self)));
+        layouts.push(layout);
+        layout
+    }
+}
+
+impl Layout {
+    fn new(geometry: Geometry) -> Layout {
+        let (w, h, k) = (geometry.width() as isize, geometry.height() as isize, geometry.line_length() as isize);
+
+        // every run of k cells in a straight line, in each of the four directions
+        let mut lines = vec![];
+        for (dx, dy) in [(1, 0), (0, 1), (1, 1), (-1, 1)] {
+            for y in 0..h {
+                for x in 0..w {
+                    let (x_end, y_end) = (x + dx * (k - 1), y + dy * (k - 1));
+                    if !(0..w).contains(&x_end) || !(0..h).contains(&y_end) { continue }
+                    lines.push((0..k).fold(0, |mask, i| mask | 1 << ((y + dy * i) * w + x + dx * i)));
+                }
+            }
+        }
+
+        let rotations: Vec<Rotation> = Rotation::ALL.iter().copied().filter(|r| r.fits(geometry)).collect();
+        let symmetries: Vec<Symmetry> = Symmetry::ALL.iter().copied().filter(|s| s.fits(geometry)).collect();
+
+        // one cell from each orbit under rotation. the front end picks a rotation that takes
+        // the player's actual first move to one of these
+        let first_moves = (0..geometry.n_cells() as u8)
+            .filter(|i| rotations.iter().all(|r| r.rotate_index(geometry, *i) >= *i))
+            .map(|i| Move(i as usize))
+            .collect();
+        let all_cells = (1 << geometry.n_cells()) - 1;
+
+        Layout { geometry, lines, all_cells, rotations, symmetries, first_moves }
+    }
+}
+
+#[test]
+fn test_layout() {
+    let standard = Geometry::STANDARD.layout();
+    assert_eq!(standard.lines.len(), 8);
+    assert_eq!(standard.symmetries.len(), 8);
+    assert_eq!(standard.first_moves.mask(), 0b000010011);
+    assert!(standard.lines.contains(&0b100010001) && standard.lines.contains(&0b001010100));
+    assert!(std::ptr::eq(standard, Geometry::STANDARD.layout()));
+
+    // 4x4, three in a row: 8 rows, 8 columns, 4 + 4 diagonals
+    let four = Geometry::new(4, 4, 3).unwrap().layout();
+    assert_eq!(four.lines.len(), 24);
+    assert_eq!(four.first_moves.iter().collect::<Vec<_>>(), [0, 1, 2, 5].map(Move));
+
+    // 5x5, four in a row: 10 rows, 10 columns, 4 + 4 diagonals
+    assert_eq!(Geometry::new(5, 5, 4).unwrap().layout().lines.len(), 28);
+
+    // rectangles can only turn around and mirror
+    let rect = Geometry::new(4, 3, 3).unwrap().layout();
+    assert_eq!(rect.rotations, vec![Rotation::Straight, Rotation::Double]);
+    assert_eq!(rect.symmetries.len(), 4);
+
+    assert!(Geometry::new(6, 5, 4).is_none());
+    assert!(Geometry::new(3, 3, 4).is_none());
+}
@@ -3,7 +3,11 @@ use std::cell::Cell;
 use rand::{thread_rng, prelude::SliceRandom};
 use wasm_bindgen::prelude::*;
 
-use crate::{game::{Board, Outcome, CellValue, Move}, consts::N_MOVES, strategy::STRATEGY, utils::set_panic_hook, rotation::Rotation};
+use crate::{
+    belief::wincon_belief, game::{Board, Outcome, CellValue, Move, IllegalMove}, notation::{GameRecord, NotationError}, consts::{MAX_CELLS, N_WINCONS}, 
+    policy::{Policy, TakeWins, SymmetricOpening, Uniform, Minimax},
+    strategy::{self, Strategy}, utils::set_panic_hook, rotation::Rotation
+};
 
 #[wasm_bindgen]
 #[allow(dead_code)]
@@ -11,6 +15,35 @@ pub fn init() {
     set_panic_hook()
 }
 
+/// Replace the strategy used for advice with one fetched at runtime.
+#[wasm_bindgen]
+pub fn load_strategy(data: &[u8]) -> Result<(), JsValue> {
+    let strategy = Strategy::from_bytes(data).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    strategy::install_strategy(strategy);
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum Advisor { Strategy, Uniform, Minimax }
+
+thread_local! {
+    static ADVISOR: Cell<Advisor> = const { Cell::new(Advisor::Strategy) };
+}
+
+/// Pick where advice comes from: "strategy" (the trained one), "uniform" or "minimax" (which can see both wincons,
+/// but only searches once `Minimax::MAX_EMPTY` cells are left, so on big boards it starts out uniform).
+#[wasm_bindgen]
+pub fn set_advisor(name: &str) -> Result<(), JsValue> {
+    let advisor = match name {
+        "strategy" => Advisor::Strategy,
+        "uniform" => Advisor::Uniform,
+        "minimax" => Advisor::Minimax,
+        _ => return Err(JsValue::from_str(&format!("unknown advisor: {}", name))),
+    };
+    ADVISOR.with(|a| a.set(advisor));
+    Ok(())
+}
+
 #[wasm_bindgen]
 impl Board {
     pub fn js_start_random() -> Board {
@@ -20,63 +53,19 @@ impl Board {
         )
     }
 
-    fn calculate_advice(&self) -> [f32; 9] {
-        // hack because for some reason it learned an asymmetric strategy for some middle cell cases and I CBA
-        // to figure out why
-        if self.turn == 0  || self.turn == 1 && self.cells[4] != CellValue::Empty { 
-            // turn 0 advice
-            // average out advice across all rotations
-            let base_advice = STRATEGY.with(|s| s.distribution(self));
-            let advices = [
-                base_advice,
-                Rotation::Left.rotate_matrix(base_advice),
-                Rotation::Double.rotate_matrix(base_advice),
-                Rotation::Right.rotate_matrix(base_advice)
-            ];
-
-            let mut avg_advice = [0.0; N_MOVES];
-            for i in 0..4 {
-                for m in 0..N_MOVES {
-                    avg_advice[m] += advices[i][m]/4.0;
-                }
-            }
-
-            avg_advice
-        } else {
-            // hack to check for moves that accomplish current player's wincon
-            // if so, that move must take place
-            let mut immediate_winning_moves = vec![];
-            for m in 0..N_MOVES as u8 {
-                let mut b2 = self.clone();
-                b2.js_play(m);
-                if let Some((_, p0, p1)) = b2.score() {
-                    let my_score = match self.next_to_move() {
-                        CellValue::P0 => p0,
-                        CellValue::P1 => p1,
-                        _ => 0,
-                    };
-                    if my_score > 0 { 
-                        immediate_winning_moves.push(m);
-                    }
-                }
-            }
-
-            let n_winning_moves = immediate_winning_moves.len();
-            if n_winning_moves > 0 {
-                let mut strat = [0.0; N_MOVES];
-                for i in immediate_winning_moves {
-                    strat[i as usize] = 1.0/n_winning_moves as f32;
-                }
-                return strat
-            }
-
-
-            self.rotation.rotate_matrix(STRATEGY.with(|s| s.distribution(self)))
-        }
+    fn calculate_advice(&self) -> [f32; MAX_CELLS] {
+        let advice = strategy::with_strategy(|s| 
+            ADVISOR.with(|a| match a.get() {
+                Advisor::Strategy => TakeWins(SymmetricOpening(s)).distribution(self),
+                Advisor::Uniform => Uniform.distribution(self),
+                Advisor::Minimax => Minimax.distribution(self),
+            })
+        );
+        self.rotation.rotate_matrix(self.geometry(), advice)
     }
 
     pub fn js_view(&self) -> View {
-        let board = self.rotation.rotate_matrix(self.cells.map(|c| 
+        let board = self.rotation.rotate_matrix(self.geometry(), self.cells().map(|c| 
             match c {
                 CellValue::Empty => 255,
                 CellValue::P0 => 0,
@@ -85,7 +74,7 @@ impl Board {
         ));
 
         let (outcome, util_p0, util_p1) = if let Some((outcome, util_p0, util_p1)) = self.score() {
-            (match outcome { Outcome::P0Win => 0, Outcome::P1Win => 1, Outcome::Tie => 2 }, util_p0 as i8, util_p1 as i8)
+            (match outcome { Outcome::P0Win => 0, Outcome::P1Win => 1, Outcome::Tie => 2 }, util_p0, util_p1)
         } else {
             (255, 0, 0)
         };
@@ -93,93 +82,86 @@ impl Board {
         let advice = 
             if outcome != 255 {  
                 // if game is over
-                [0.0; N_MOVES]
+                [0.0; MAX_CELLS]
             } else {
                 self.calculate_advice()
             };
 
-        let wants_p0 = match self.p0_wants {
-            Outcome::Tie => 2,
-            Outcome::P0Win => 0,
-            Outcome::P1Win => 1,
-        };
-
-        let wants_p1 = match self.p1_wants {
-            Outcome::Tie => 2,
-            Outcome::P0Win => 0,
-            Outcome::P1Win => 1,
-        };
+        let wants_p0 = wants_code(self.p0_wants);
+        let wants_p1 = wants_code(self.p1_wants);
+
+        // reorder from Outcome::ALL into the same codes as wants_p0/wants_p1.
+        // players are modelled as following the strategy's advice, whichever advisor is showing
+        let (belief_p0, belief_p1) = strategy::with_strategy(|s| {
+            let model = TakeWins(SymmetricOpening(s));
+            let to_codes = |belief: [f32; N_WINCONS]| {
+                let mut out = [0.0; N_WINCONS];
+                for (o, b) in Outcome::ALL.iter().zip(belief.iter()) { out[wants_code(*o) as usize] = *b }
+                out
+            };
+            (
+                to_codes(wincon_belief(&model, self, CellValue::P0)), 
+                to_codes(wincon_belief(&model, self, CellValue::P1)),
+            )
+        });
 
         View { 
-            player_turn: (self.turn % 2) as u8,
+            width: self.geometry().width() as u8,
+            height: self.geometry().height() as u8,
+            player_turn: self.turn % 2,
             board,
             advice,
             outcome, util_p0, util_p1, wants_p0, wants_p1,
+            belief_p0, belief_p1,
         }
     }
 
-    pub fn js_play(&mut self, m: u8) {
+    /// Play where the player clicked. Throws (with a description of what was wrong) if it isn't a legal move.
+    pub fn js_play(&mut self, m: u8) -> Result<(), JsValue> {
+        let to_js = |e: IllegalMove| JsValue::from_str(&e.to_string());
+        if m as usize >= self.n_cells() { return Err(to_js(IllegalMove::OutOfRange { index: m as usize })) }
+        let geometry = self.geometry();
+
         if self.turn == 0 {
-            // all moves are in principle possible
-            if m == 0 { self.play(Move(0)); }
-            else if m == 1 { self.play(Move(1)); }
-            else if m == 2 { 
-                self.rotation = Rotation::Left;
-                self.play(Move(0)); 
-            }
-            else if m == 5 { 
-                self.rotation = Rotation::Left;
-                self.play(Move(1)); 
-            }
-            else if m == 8 { 
-                self.rotation = Rotation::Double;
-                self.play(Move(0)); 
-            }
-            else if m == 7 { 
-                self.rotation = Rotation::Double;
-                self.play(Move(1)); 
-            }
-            else if m == 6 { 
-                self.rotation = Rotation::Right;
-                self.play(Move(0)); 
-            }
-            else if m == 3 { 
-                self.rotation = Rotation::Right;
-                self.play(Move(1)); 
-            }
-            else if m == 4 { 
-                self.rotation = *[
-                    Rotation::Straight,
-                    Rotation::Right,
-                    Rotation::Double,
-                    Rotation::Left,
-                ].choose(&mut thread_rng()).unwrap();
-                self.play(Move(4));
-            } else {
-                panic!("invalid move: {}", m)
-            }
-            return
+            // all moves are in principle possible: turn the board so the move lands on one the engine knows
+            let rotations: Vec<Rotation> = self.layout.rotations.iter().copied()
+                .filter(|r| self.layout.first_moves.contains(Move(r.derotate_index(geometry, m) as usize)))
+                .collect();
+            let rotation = *rotations.choose(&mut thread_rng())
+                .expect("every cell is in some rotation's first moves");
+            self.rotation = rotation;
         }
 
-        let m = self.rotation.derotate_index(m);
-        
+        let m = self.rotation.derotate_index(geometry, m);
+        self.try_play(Move(m as usize)).map_err(to_js)
+    }
+
+    /// The game so far as a `GameRecord`, for saving or for pasting into bug reports and native tests.
+    /// It has the wincons, the rotation and every move, so `js_deserialize` gets back the same game.
+    pub fn js_serialize(&self) -> String {
+        self.record().to_string()
+    }
 
-        // explicitly handle errors by doing nothing
-        if self.turn as usize >= N_MOVES { return; }
-        if m as usize >= N_MOVES { return; }
-        if self.cells[m as usize] != CellValue::Empty { return; }
-        if self.score().is_some() { return; }
-        if !self.possible_moves().contains(&Move(m as usize)) { return; }
+    /// Resume a game saved with `js_serialize`. Throws if the text isn't a game we can replay,
+    /// including one turned a quarter turn on a board that isn't square.
+    pub fn js_deserialize(text: &str) -> Result<Board, JsValue> {
+        let record: GameRecord = text.parse().map_err(|e: NotationError| JsValue::from_str(&e.to_string()))?;
+        record.replay().map_err(|e| JsValue::from_str(&e.to_string()))
+    }
 
-        self.play(Move(m as usize))
+    /// Take back the last move. Returns false if there wasn't one.
+    pub fn js_undo(&mut self) -> bool {
+        self.undo().is_some()
     }
 }
 
 #[wasm_bindgen]
 pub struct View {
+    pub width: u8,
+    pub height: u8,
     pub player_turn: u8, // 0 for p0, 1 for p1
-    board: [u8; N_MOVES], // 255 for empty, 0 for p0, 1 for p1
-    advice: [f32; N_MOVES], // 255 for empty, 0 for p0, 1 for p1
+    board: [u8; MAX_CELLS], // 255 for empty, 0 for p0, 1 for p1. cells are numbered row by row
+    advice: [f32; MAX_CELLS], // 255 for empty, 0 for p0, 1 for p1
 
     // 0 for p0 wins, 1 for p1 wins, 2 for a tie, 255 otherwise
     pub outcome: u8,
@@ -187,15 +169,50 @@ pub struct View {
     pub util_p1: i8,
     pub wants_p0: u8, // 0 for p0, 1 for p1, 2 for a tie
     pub wants_p1: u8, // 0 for p0, 1 for p1, 2 for a tie
+
+    // how likely each player is to want each outcome, judging by their moves. indexed like wants_p0
+    belief_p0: [f32; N_WINCONS],
+    belief_p1: [f32; N_WINCONS],
 }
 
 #[wasm_bindgen]
 impl View {
     pub fn get_cell(&self, i: usize) -> u8 {
-        self.board.get(i).cloned().unwrap_or_else(|| 255)
+        self.board.get(i).cloned().unwrap_or(255)
     }
 
     pub fn get_advice(&self, i: usize) -> f32 {
-        self.advice.get(i).cloned().unwrap_or_else(|| 0.0)
+        self.advice.get(i).cloned().unwrap_or(0.0)
+    }
+
+    pub fn get_belief_p0(&self, wants: usize) -> f32 {
+        self.belief_p0.get(wants).cloned().unwrap_or(0.0)
     }
-}
\ No newline at end of file
+
+    pub fn get_belief_p1(&self, wants: usize) -> f32 {
+        self.belief_p1.get(wants).cloned().unwrap_or(0.0)
+    }
+}
+
+fn wants_code(o: Outcome) -> u8 {
+    match o {
+        Outcome::Tie => 2,
+        Outcome::P0Win => 0,
+        Outcome::P1Win => 1,
+    }
+}
+
+#[test]
+fn test_deserialize_rotation() {
+    // a save of a 4x3 game the front end has turned upside down
+    let mut board = Board::with_geometry(crate::geometry::Geometry::new(4, 3, 3).unwrap(), Outcome::P0Win, Outcome::Tie);
+    for m in [5, 0, 6] { board.play(Move(m)) }
+    board.rotation = Rotation::Double;
+    let saved = board.js_serialize();
+    let (view, back) = (board.js_view(), Board::js_deserialize(&saved).unwrap().js_view());
+    assert!((0..12).all(|i| back.get_cell(i) == view.get_cell(i)));
+
+    // edited to a quarter turn, it's refused before it can get as far as js_view
+    let edited = saved.replace(" r2 ", " r1 ");
+    assert!(edited.parse::<GameRecord>().is_err());
+}
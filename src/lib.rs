@@ -1,10 +1,26 @@
+pub mod belief;
 mod consts;
+mod geometry;
+pub mod exploitability;
 mod game;
 mod jsffi;
+pub mod notation;
+pub mod policy;
 mod rotation;
-mod strategy;
+#[cfg(feature = "serde")]
+mod serialize;
+pub mod strategy;
 mod utils;
 
+// the native API. everything else is reached through the public modules
+pub use consts::{MAX_CELLS, N_WINCONS};
+pub use game::{Board, CellValue, IllegalMove, InfosetKey, Move, MoveSet, Outcome};
+pub use geometry::Geometry;
+pub use notation::{GameRecord, NotationError};
+pub use policy::Policy;
+pub use rotation::Symmetry;
+pub use strategy::{Strategy, StrategyError, StrategyHeader};
+
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
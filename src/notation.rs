@@ -0,0 +1,414 @@
+//! Text notation for positions and games, for bug reports and test fixtures.
+//!
+//! A position (`Board`'s `Display`/`FromStr`) is five fields separated by spaces:
+//! the cells row by row, with rows separated by `/` and `x`, `o` or `.` for p0, p1 or empty;
+//! the side to move (`x` or `o`); both wincons (`x`, `o` or `tie`, p0's first, separated by a comma);
+//! the front end's rotation in quarter turns (`r0` to `r3`); and the line length (`k3`).
+//! For example, `x.o/.x./... o x,tie r0 k3`.
+//!
+//! A position doesn't say what order the moves were played in, so a parsed board makes one up:
+//! it alternates between p0's and p1's cells in cell order, except that cells in a finished line
+//! go last, since the line has to be what ended the game. The engine always opens on one of the
+//! layout's first moves, so if p0's first cell isn't one, the cells are turned until it is and the
+//! rotation is adjusted to match, which means the front end shows the same board but `Display` may
+//! not give back the text it was parsed from. Strategies look boards up by their history, so advice
+//! for a parsed position can differ from advice in the game it came from. Use a `GameRecord` when that matters.
+//!
+//! A geometry is the width, height and line length, like `3x3k3`.
+//!
+//! A game record is the geometry, the wincons and rotation like above, the moves in
+//! engine orientation, then `=` and the score: the outcome and both utilities, or `*` if the game
+//! isn't over. For example, `3x3k3 x,tie r1 4 0 2 8 6 = x 1 0`.
+
+use std::{fmt, str::FromStr};
+
+use crate::{game::{Board, CellValue, IllegalMove, Move, Outcome}, geometry::Geometry, rotation::Rotation};
+
+/// Why some notation couldn't be read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NotationError {
+    /// A field is missing, malformed, or there's text after the last one.
+    BadField { field: &'static str, text: String },
+    /// The rows aren't all the same length, or make a board we can't play on.
+    BadBoard,
+    /// The cells can't have come from a game: the counts are off, both players have lines,
+    /// or it's the wrong side to move.
+    ImpossiblePosition,
+    /// A recorded move can't be played. `ply` counts from 0.
+    IllegalMove { ply: usize, error: IllegalMove },
+    /// Replaying a record doesn't give the score it says it has.
+    WrongScore,
+}
+
+impl fmt::Display for NotationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotationError::BadField { field, text } => write!(f, "bad {}: {:?}", field, text),
+            NotationError::BadBoard => write!(f, "the rows don't make a board we can play on"),
+            NotationError::ImpossiblePosition => write!(f, "the position can't come from a real game"),
+            NotationError::IllegalMove { ply, error } => write!(f, "move {} is illegal: {}", ply, error),
+            NotationError::WrongScore => write!(f, "the game doesn't end with the score it says"),
+        }
+    }
+}
+
+impl std::error::Error for NotationError {}
+
+fn bad(field: &'static str, text: Option<&str>) -> NotationError {
+    NotationError::BadField { field, text: text.unwrap_or_default().to_string() }
+}
+
+fn outcome_code(o: Outcome) -> &'static str {
+    match o {
+        Outcome::P0Win => "x",
+        Outcome::P1Win => "o",
+        Outcome::Tie => "tie",
+    }
+}
+
+fn parse_outcome(text: &str) -> Option<Outcome> {
+    match text {
+        "x" => Some(Outcome::P0Win),
+        "o" => Some(Outcome::P1Win),
+        "tie" => Some(Outcome::Tie),
+        _ => None,
+    }
+}
+
+fn parse_wincons(text: Option<&str>) -> Result<(Outcome, Outcome), NotationError> {
+    let (p0, p1) = text.and_then(|t| t.split_once(',')).ok_or_else(|| bad("wincons", text))?;
+    match (parse_outcome(p0), parse_outcome(p1)) {
+        (Some(p0), Some(p1)) => Ok((p0, p1)),
+        _ => Err(bad("wincons", text)),
+    }
+}
+
+fn parse_rotation(text: Option<&str>) -> Result<Rotation, NotationError> {
+    match text.and_then(|t| t.strip_prefix('r')).and_then(|t| t.parse::<u8>().ok()) {
+        Some(turns) if turns < 4 => Ok(Rotation::from_quarter_turns(turns)),
+        _ => Err(bad("rotation", text)),
+    }
+}
+
+// quarter turns only make sense on square boards: anything else would come out a different shape
+fn check_rotation(rotation: Rotation, geometry: Geometry) -> Result<(), NotationError> {
+    if rotation.fits(geometry) { return Ok(()) }
+    Err(bad("rotation", Some(&format!("r{}", rotation.quarter_turns()))))
+}
+
+fn parse_number<T: FromStr>(field: &'static str, text: Option<&str>) -> Result<T, NotationError> {
+    text.and_then(|t| t.parse().ok()).ok_or_else(|| bad(field, text))
+}
+
+fn check_done<'a>(mut fields: impl Iterator<Item = &'a str>) -> Result<(), NotationError> {
+    match fields.next() {
+        Some(extra) => Err(bad("end of notation", Some(extra))),
+        None => Ok(()),
+    }
+}
+
+impl fmt::Display for Geometry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}k{}", self.width(), self.height(), self.line_length())
+    }
+}
+
+impl FromStr for Geometry {
+    type Err = NotationError;
+
+    fn from_str(s: &str) -> Result<Geometry, NotationError> {
+        let (width, height, line_length) = s.split_once('x')
+            .and_then(|(width, rest)| {
+                let (height, line_length) = rest.split_once('k')?;
+                Some((width.parse().ok()?, height.parse().ok()?, line_length.parse().ok()?))
+            })
+            .ok_or_else(|| bad("geometry", Some(s)))?;
+        Geometry::new(width, height, line_length).ok_or(NotationError::BadBoard)
+    }
+}
+
+impl fmt::Display for Board {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let geometry = self.geometry();
+        for y in 0..geometry.height() {
+            if y > 0 { write!(f, "/")? }
+            for x in 0..geometry.width() {
+                let c = match self.cell(y * geometry.width() + x) {
+                    CellValue::P0 => 'x',
+                    CellValue::P1 => 'o',
+                    CellValue::Empty => '.',
+                };
+                write!(f, "{}", c)?;
+            }
+        }
+        let to_move = if self.next_to_move() == CellValue::P0 { "x" } else { "o" };
+        write!(
+            f, " {} {},{} r{} k{}", 
+            to_move, outcome_code(self.p0_wants), outcome_code(self.p1_wants), 
+            self.rotation.quarter_turns(), geometry.line_length()
+        )
+    }
+}
+
+impl FromStr for Board {
+    type Err = NotationError;
+
+    fn from_str(s: &str) -> Result<Board, NotationError> {
+        let mut fields = s.split_whitespace();
+        let cells = fields.next().ok_or_else(|| bad("cells", None))?;
+        let to_move = fields.next();
+        let (p0_wants, p1_wants) = parse_wincons(fields.next())?;
+        let rotation = parse_rotation(fields.next())?;
+        let k = fields.next();
+        let line_length = parse_number("line length", k.and_then(|k| k.strip_prefix('k')))?;
+        check_done(fields)?;
+
+        let rows: Vec<&str> = cells.split('/').collect();
+        let width = rows[0].len();
+        if rows.iter().any(|r| r.len() != width) { return Err(NotationError::BadBoard) }
+        let geometry = Geometry::new(width, rows.len(), line_length).ok_or(NotationError::BadBoard)?;
+        check_rotation(rotation, geometry)?;
+
+        let mut played = [vec![], vec![]];
+        for (i, c) in rows.concat().chars().enumerate() {
+            match c {
+                'x' => played[0].push(Move(i)),
+                'o' => played[1].push(Move(i)),
+                '.' => {}
+                _ => return Err(bad("cells", Some(cells))),
+            }
+        }
+
+        let expected_to_move = match played[0].len().checked_sub(played[1].len()) {
+            Some(0) => "x",
+            Some(1) => "o",
+            _ => return Err(NotationError::ImpossiblePosition),
+        };
+        match to_move {
+            Some("x") | Some("o") if to_move == Some(expected_to_move) => {}
+            Some("x") | Some("o") => return Err(NotationError::ImpossiblePosition),
+            _ => return Err(bad("side to move", to_move)),
+        }
+
+        // make up an order for the moves, since the position doesn't say. a player's cells that are in
+        // more of their finished lines go later, so a line can only be finished by their last move
+        let layout = geometry.layout();
+        for cells in played.iter_mut() {
+            let mask = cells.iter().fold(0u32, |mask, m| mask | 1 << m.0);
+            cells.sort_by_key(|m| layout.lines.iter().filter(|line| mask & *line == **line && *line & 1 << m.0 != 0).count());
+        }
+
+        // turn the board so p0's first move is one the engine plays, and turn the rotation back the other way
+        let turned = match played[0].first() {
+            Some(first) => *layout.rotations.iter()
+                .find(|r| layout.first_moves.contains(Move(r.rotate_index(geometry, first.0 as u8) as usize)))
+                .expect("every cell turns into one of the first moves"),
+            None => Rotation::Straight,
+        };
+
+        let mut moves = vec![];
+        for i in 0..played[0].len() {
+            moves.push(played[0][i]);
+            if let Some(m) = played[1].get(i) { moves.push(*m) }
+        }
+
+        // replaying checks that the made-up order is a game: nobody plays on after a line
+        let mut board = Board::with_geometry(geometry, p0_wants, p1_wants);
+        for m in moves {
+            let m = Move(turned.rotate_index(geometry, m.0 as u8) as usize);
+            board.try_play(m).map_err(|_| NotationError::ImpossiblePosition)?;
+        }
+        board.rotation = Rotation::from_quarter_turns(rotation.quarter_turns() + 4 - turned.quarter_turns());
+        Ok(board)
+    }
+}
+
+/// A whole game, in enough detail to replay it exactly. See the module docs for the text format.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GameRecord {
+    geometry: Geometry,
+    p0_wants: Outcome,
+    p1_wants: Outcome,
+    rotation: Rotation,
+    moves: Vec<Move>,
+    score: Option<(Outcome, i8, i8)>,
+}
+
+impl Board {
+    /// Everything needed to play this game again.
+    pub fn record(&self) -> GameRecord {
+        GameRecord {
+            geometry: self.geometry(),
+            p0_wants: self.p0_wants,
+            p1_wants: self.p1_wants,
+            rotation: self.rotation,
+            moves: self.moves(),
+            score: self.score(),
+        }
+    }
+}
+
+impl GameRecord {
+    pub fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    /// The moves, in engine orientation.
+    pub fn moves(&self) -> &[Move] {
+        &self.moves
+    }
+
+    pub fn score(&self) -> Option<(Outcome, i8, i8)> {
+        self.score
+    }
+
+    /// Play the game again, checking the rotation, every move and the final score.
+    pub fn replay(&self) -> Result<Board, NotationError> {
+        check_rotation(self.rotation, self.geometry)?;
+        let mut board = Board::with_geometry(self.geometry, self.p0_wants, self.p1_wants);
+        for (ply, m) in self.moves.iter().enumerate() {
+            board.try_play(*m).map_err(|error| NotationError::IllegalMove { ply, error })?;
+        }
+        board.rotation = self.rotation;
+        if board.score() != self.score { return Err(NotationError::WrongScore) }
+        Ok(board)
+    }
+}
+
+impl fmt::Display for GameRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "{} {},{} r{}", 
+            self.geometry, outcome_code(self.p0_wants), outcome_code(self.p1_wants), self.rotation.quarter_turns()
+        )?;
+        for m in self.moves.iter() {
+            write!(f, " {}", m.0)?;
+        }
+        match self.score {
+            Some((outcome, p0, p1)) => write!(f, " = {} {} {}", outcome_code(outcome), p0, p1),
+            None => write!(f, " = *"),
+        }
+    }
+}
+
+impl FromStr for GameRecord {
+    type Err = NotationError;
+
+    fn from_str(s: &str) -> Result<GameRecord, NotationError> {
+        let mut fields = s.split_whitespace();
+
+        let geometry = fields.next().ok_or_else(|| bad("geometry", None))?.parse()?;
+
+        let (p0_wants, p1_wants) = parse_wincons(fields.next())?;
+        let rotation = parse_rotation(fields.next())?;
+
+        let mut moves = vec![];
+        loop {
+            match fields.next() {
+                Some("=") => break,
+                text => moves.push(Move(parse_number("move", text)?)),
+            }
+        }
+
+        let outcome_text = fields.next();
+        let score = match outcome_text {
+            Some("*") => None,
+            _ => {
+                let outcome = outcome_text.and_then(parse_outcome).ok_or_else(|| bad("score", outcome_text))?;
+                Some((outcome, parse_number("score", fields.next())?, parse_number("score", fields.next())?))
+            }
+        };
+        check_done(fields)?;
+
+        let record = GameRecord { geometry, p0_wants, p1_wants, rotation, moves, score };
+        record.replay()?;
+        Ok(record)
+    }
+}
+
+#[test]
+fn test_board_notation() {
+    let board: Board = "x.o/.x./... o x,tie r0 k3".parse().unwrap();
+    assert_eq!(board.to_string(), "x.o/.x./... o x,tie r0 k3");
+    assert_eq!(board.moves(), [0, 2, 4].map(Move));
+    assert_eq!(board.next_to_move(), CellValue::P1);
+
+    let mut board = Board::with_geometry(Geometry::new(4, 3, 3).unwrap(), Outcome::P1Win, Outcome::Tie);
+    for m in [0, 5, 11] { board.play(Move(m)) }
+    board.rotation = Rotation::Double;
+    assert_eq!(board.to_string(), "x.../.o../...x o o,tie r2 k3");
+    assert_eq!(board.to_string().parse::<Board>().unwrap().to_string(), board.to_string());
+
+    assert_eq!("x.o/.x./.. o x,tie r0 k3".parse::<Board>().err(), Some(NotationError::BadBoard));
+    assert_eq!("x.o/.x./... x x,tie r0 k3".parse::<Board>().err(), Some(NotationError::ImpossiblePosition));
+    assert_eq!("xxx/ooo/... x x,o r0 k3".parse::<Board>().err(), Some(NotationError::ImpossiblePosition));
+    // p0's line has to be what ended the game, so p1 can't have moved since
+    assert_eq!("xxx/oo./o.. x tie,tie r0 k3".parse::<Board>().err(), Some(NotationError::ImpossiblePosition));
+
+    // the engine can't open in a corner other than 0, so the board turns and the rotation turns back
+    let board: Board = "..x/.../... o tie,tie r0 k3".parse().unwrap();
+    assert_eq!(board.to_string(), "x../.../... o tie,tie r3 k3");
+
+    // a line's cells are played last, so a finished game comes out finished
+    let board: Board = "xxx/oo./x.o o tie,tie r0 k3".parse().unwrap();
+    assert_eq!(board.score(), Some((Outcome::P0Win, 0, 0)));
+
+    // whatever order a board makes up, it can be recorded and read back
+    for text in ["x.o/.x./... o x,tie r0 k3", "..x/.../... o tie,tie r0 k3", "xxx/oo./x.o o tie,tie r0 k3", ".o../x... x x,o r2 k3"] {
+        let board: Board = text.parse().unwrap();
+        let record: GameRecord = board.record().to_string().parse().unwrap();
+        assert_eq!(record, board.record());
+        assert_eq!(record.replay().unwrap().to_string(), board.to_string());
+    }
+    assert_eq!(
+        "x.o/.x./... o x,win r0 k3".parse::<Board>().err(), 
+        Some(NotationError::BadField { field: "wincons", text: "x,win".to_string() })
+    );
+    assert_eq!(
+        "x.o/.x./... o x,tie r0 k3 extra".parse::<Board>().err(), 
+        Some(NotationError::BadField { field: "end of notation", text: "extra".to_string() })
+    );
+}
+
+#[test]
+fn test_game_record() {
+    let mut board = Board::new(Outcome::P0Win, Outcome::Tie);
+    for m in [4, 0, 2, 8, 6] { board.play(Move(m)) }
+    board.rotation = Rotation::Right;
+
+    let text = board.record().to_string();
+    assert_eq!(text, "3x3k3 x,tie r1 4 0 2 8 6 = x 1 0");
+    let record: GameRecord = text.parse().unwrap();
+    assert_eq!(record, board.record());
+    let replayed = record.replay().unwrap();
+    assert_eq!((replayed.to_string(), replayed.moves()), (board.to_string(), board.moves()));
+
+    assert_eq!("3x3k3".parse::<Geometry>(), Ok(Geometry::STANDARD));
+    assert_eq!(Geometry::new(4, 3, 3).unwrap().to_string(), "4x3k3");
+    assert_eq!("3x3".parse::<Geometry>().err(), Some(NotationError::BadField { field: "geometry", text: "3x3".to_string() }));
+    assert_eq!("6x5k4".parse::<Geometry>().err(), Some(NotationError::BadBoard));
+
+    // unfinished games work too
+    assert_eq!("3x3k3 tie,o r0 1 4 = *".parse::<GameRecord>().unwrap().moves(), [1, 4].map(Move));
+
+    assert_eq!("3x3k3 x,tie r1 4 0 2 8 6 = x 0 1".parse::<GameRecord>().err(), Some(NotationError::WrongScore));
+    assert_eq!(
+        "3x3k3 x,tie r1 4 4 = *".parse::<GameRecord>().err(), 
+        Some(NotationError::IllegalMove { ply: 1, error: IllegalMove::Occupied { index: 4 } })
+    );
+    assert_eq!(
+        "3x3k3 x,tie r1 4 0".parse::<GameRecord>().err(), 
+        Some(NotationError::BadField { field: "move", text: "".to_string() })
+    );
+
+    // a quarter turn would change the shape of a board that isn't square
+    assert!("4x3k3 x,tie r2 5 0 6 = *".parse::<GameRecord>().is_ok());
+    assert_eq!(
+        "4x3k3 x,tie r1 5 0 6 = *".parse::<GameRecord>().err(), 
+        Some(NotationError::BadField { field: "rotation", text: "r1".to_string() })
+    );
+    assert_eq!(
+        "x.../.o../...x o o,tie r3 k3".parse::<Board>().err(), 
+        Some(NotationError::BadField { field: "rotation", text: "r3".to_string() })
+    );
+}
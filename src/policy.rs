@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+
+use crate::{consts::MAX_CELLS, game::{Board, CellValue, MoveSet, Outcome}, strategy::Strategy};
+
+/// Something that can pick moves.
+pub trait Policy {
+    /// How likely the player to move is to play each cell, in engine orientation.
+    /// Moves that aren't in `board.possible_moves()` should get zero, unless the policy says otherwise.
+    fn distribution(&self, board: &Board) -> [f32; MAX_CELLS];
+}
+
+impl<P: Policy + ?Sized> Policy for &P {
+    fn distribution(&self, board: &Board) -> [f32; MAX_CELLS] {
+        (**self).distribution(board)
+    }
+}
+
+impl<P: Policy + ?Sized> Policy for Box<P> {
+    fn distribution(&self, board: &Board) -> [f32; MAX_CELLS] {
+        (**self).distribution(board)
+    }
+}
+
+/// The trained CFR table.
+impl Policy for Strategy {
+    fn distribution(&self, board: &Board) -> [f32; MAX_CELLS] {
+        Strategy::distribution(self, board)
+    }
+}
+
+/// Every legal move is equally likely.
+pub struct Uniform;
+
+impl Policy for Uniform {
+    fn distribution(&self, board: &Board) -> [f32; MAX_CELLS] {
+        uniform_over(board.possible_moves())
+    }
+}
+
+/// Plays perfectly, but cheats: it can see both players' wincons.
+/// It treats the game as zero-sum, scoring each ending as (my utility - your utility),
+/// and picks uniformly among the best moves.
+///
+/// It searches every game from the current position, so it only does that once at most
+/// `Minimax::MAX_EMPTY` cells are left. Until then (which on 3x3 is never) it plays uniformly.
+pub struct Minimax;
+
+impl Minimax {
+    /// The most empty cells it will search from: a whole 4x3 board, or the last 12 moves of a bigger one.
+    pub const MAX_EMPTY: usize = 12;
+}
+
+impl Policy for Minimax {
+    fn distribution(&self, board: &Board) -> [f32; MAX_CELLS] {
+        let moves = board.possible_moves();
+        if board.n_cells() - board.turn as usize > Minimax::MAX_EMPTY {
+            return uniform_over(moves)
+        }
+
+        // the wincons don't change during a search, so the cells and turn are enough to identify a position
+        let mut memo = HashMap::new();
+        let values: Vec<i8> = moves.iter().map(|m| {
+            let mut b2 = board.clone();
+            b2.play(m);
+            -negamax(&b2, &mut memo)
+        }).collect();
+
+        let best = values.iter().copied().max();
+        let best_moves: MoveSet = moves.iter().zip(values.iter())
+            .filter(|(_, v)| Some(**v) == best)
+            .map(|(m, _)| m)
+            .collect();
+        uniform_over(best_moves)
+    }
+}
+
+// value of the position for the player to move
+fn negamax(board: &Board, memo: &mut HashMap<([u32; 2], u8), i8>) -> i8 {
+    if let Some((_, p0, p1)) = board.score() {
+        let util_p0 = p0 - p1;
+        return if board.next_to_move() == CellValue::P0 { util_p0 } else { -util_p0 }
+    }
+    if let Some(value) = memo.get(&(board.masks, board.turn)) {
+        return *value
+    }
+
+    let mut best = i8::MIN;
+    for m in board.possible_moves() {
+        let mut b2 = board.clone();
+        b2.play(m);
+        best = best.max(-negamax(&b2, memo));
+    }
+    memo.insert((board.masks, board.turn), best);
+    best
+}
+
+/// If the player to move can get what they want right now, do that. Otherwise defer to the inner policy.
+pub struct TakeWins<P>(pub P);
+
+impl<P: Policy> Policy for TakeWins<P> {
+    fn distribution(&self, board: &Board) -> [f32; MAX_CELLS] {
+        let me = board.next_to_move();
+        let (wants, my_win) = if me == CellValue::P0 { (board.p0_wants, Outcome::P0Win) } else { (board.p1_wants, Outcome::P1Win) };
+        let winning_moves = if wants == my_win {
+            board.winning_moves(me)
+        } else if wants == Outcome::Tie && board.turn as usize + 1 == board.n_cells() {
+            // the last move ties unless it wins
+            MoveSet::from_mask(board.legal_mask() & !board.winning_moves(me).mask())
+        } else {
+            MoveSet::EMPTY
+        };
+
+        if winning_moves.is_empty() {
+            self.0.distribution(board)
+        } else {
+            uniform_over(winning_moves)
+        }
+    }
+}
+
+/// While the board looks the same from every rotation, average the inner policy over all of them.
+///
+/// The trained strategy is asymmetric in some of these positions (for reasons nobody has figured out),
+/// so this smooths it out. Note that on turn 0 this spreads probability onto cells that are only legal
+/// before the front end's rotation trick, so it's meant for advice rather than for driving `Board::play`.
+pub struct SymmetricOpening<P>(pub P);
+
+impl<P: Policy> Policy for SymmetricOpening<P> {
+    fn distribution(&self, board: &Board) -> [f32; MAX_CELLS] {
+        let base = self.0.distribution(board);
+
+        let geometry = board.geometry();
+        let rotations = &board.layout.rotations;
+        let cells = board.cells();
+        if !rotations.iter().all(|r| r.rotate_matrix(geometry, cells) == cells) {
+            return base
+        }
+
+        let mut avg = [0.0; MAX_CELLS];
+        for r in rotations.iter() {
+            let rotated = r.rotate_matrix(geometry, base);
+            for m in 0..board.n_cells() {
+                avg[m] += rotated[m] / rotations.len() as f32;
+            }
+        }
+        avg
+    }
+}
+
+/// Play like `first`, except with probability `weight` play like `second` instead.
+pub struct Mix<A, B> {
+    pub first: A,
+    pub second: B,
+    pub weight: f32,
+}
+
+impl<A: Policy, B: Policy> Policy for Mix<A, B> {
+    fn distribution(&self, board: &Board) -> [f32; MAX_CELLS] {
+        let a = self.first.distribution(board);
+        let b = self.second.distribution(board);
+        let mut out = [0.0; MAX_CELLS];
+        for m in 0..board.n_cells() {
+            out[m] = a[m] * (1.0 - self.weight) + b[m] * self.weight;
+        }
+        out
+    }
+}
+
+/// A distribution that picks each of `moves` with equal probability.
+pub fn uniform_over(moves: MoveSet) -> [f32; MAX_CELLS] {
+    let mut s = [0.0; MAX_CELLS];
+    for m in moves {
+        s[m.0] = 1.0 / moves.len() as f32;
+    }
+    s
+}
+
+#[cfg(test)]
+fn play_all(board: &mut Board, moves: &[usize]) {
+    use crate::game::Move;
+    for m in moves { board.play(Move(*m)) }
+}
+
+#[test]
+fn test_take_wins() {
+    // p0 has 0 and 1, p1 has 4 and 8: p0 can complete the top row
+    let mut board = Board::new(Outcome::P0Win, Outcome::P1Win);
+    play_all(&mut board, &[0, 4, 1, 8]);
+    assert_eq!(TakeWins(Uniform).distribution(&board), uniform_over(MoveSet::from_mask(1 << 2)));
+
+    // but if p0 doesn't want to win, it defers
+    let mut board = Board::new(Outcome::Tie, Outcome::P1Win);
+    play_all(&mut board, &[0, 4, 1, 8]);
+    assert_eq!(TakeWins(Uniform).distribution(&board), Uniform.distribution(&board));
+}
+
+#[test]
+fn test_minimax() {
+    // p1 threatens 0-4-8, so p0 has to block
+    let mut board = Board::new(Outcome::P0Win, Outcome::P1Win);
+    play_all(&mut board, &[1, 4, 5, 8]);
+    assert_eq!(Minimax.distribution(&board), uniform_over(MoveSet::from_mask(1 << 0)));
+
+    // everyone wants a tie: any opening is fine, since perfect play ties from anywhere
+    let board = Board::new(Outcome::Tie, Outcome::Tie);
+    assert_eq!(Minimax.distribution(&board), Uniform.distribution(&board));
+
+    // a 4x3 board is small enough to search from the start. here p0 wants a tie and has to stop p1's 4-5-6
+    let mut board = Board::with_geometry(crate::geometry::Geometry::new(4, 3, 3).unwrap(), Outcome::Tie, Outcome::P1Win);
+    play_all(&mut board, &[0, 4, 11, 5]);
+    assert_eq!(Minimax.distribution(&board), uniform_over(MoveSet::from_mask(1 << 6)));
+
+    // a 5x5 one isn't, so it doesn't try
+    let board = Board::with_geometry(crate::geometry::Geometry::new(5, 5, 4).unwrap(), Outcome::P0Win, Outcome::P1Win);
+    assert_eq!(Minimax.distribution(&board), Uniform.distribution(&board));
+}
+
+#[test]
+fn test_symmetric_opening() {
+    let board = Board::new(Outcome::Tie, Outcome::P0Win);
+    let dist = SymmetricOpening(Uniform).distribution(&board);
+    // a third on corners, a third on edges, a third on the center
+    assert!((dist[0] - 1.0 / 12.0).abs() < 1e-6);
+    assert!((dist[7] - 1.0 / 12.0).abs() < 1e-6);
+    assert!((dist[4] - 1.0 / 3.0).abs() < 1e-6);
+}
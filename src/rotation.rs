@@ -1,67 +1,231 @@
-use crate::consts::N_MOVES;
+use crate::{consts::MAX_CELLS, geometry::Geometry};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) enum Rotation {
     Straight, Right, Double, Left
 }
 
+/// One of the eight symmetries of a square board: a rotation, optionally preceded by a left-right mirror.
+/// Boards that aren't square only have the four that don't involve a quarter turn.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Symmetry {
+    rotation: Rotation,
+    reflected: bool,
+}
+
 impl Rotation {
-    pub fn rotate_index(&self, index: u8) -> u8 {
-        assert!((0..N_MOVES as u8).contains(&index));
+    pub const ALL: [Rotation; 4] = [Rotation::Straight, Rotation::Right, Rotation::Double, Rotation::Left];
+
+    // how many times you'd have to apply Right to get this
+    pub(crate) fn quarter_turns(self) -> u8 {
         match self {
-            Rotation::Straight => index,
-            Rotation::Right => [6, 3, 0, 7, 4, 1, 8, 5, 2][index as usize],
-            Rotation::Double => 8 - index,
-            Rotation::Left => [2, 5, 8, 1, 4, 7, 0, 3, 6][index as usize],
+            Rotation::Straight => 0,
+            Rotation::Right => 1,
+            Rotation::Double => 2,
+            Rotation::Left => 3,
         }
     }
 
-    pub fn derotate_index(&self, index: u8) -> u8{
+    pub(crate) fn from_quarter_turns(turns: u8) -> Rotation {
+        Rotation::ALL[(turns % 4) as usize]
+    }
+
+    /// Quarter turns would change the shape of a board that isn't square.
+    pub fn fits(&self, geometry: Geometry) -> bool {
+        geometry.is_square() || matches!(self, Rotation::Straight | Rotation::Double)
+    }
+
+    pub fn rotate_index(&self, geometry: Geometry, index: u8) -> u8 {
+        assert!((index as usize) < geometry.n_cells() && self.fits(geometry));
+        let (w, h) = (geometry.width() as u8, geometry.height() as u8);
+        let (x, y) = (index % w, index / w);
+        let (x2, y2) = match self {
+            Rotation::Straight => (x, y),
+            Rotation::Right => (y, w - 1 - x),
+            Rotation::Double => (w - 1 - x, h - 1 - y),
+            Rotation::Left => (h - 1 - y, x),
+        };
+        y2 * w + x2
+    }
+
+    pub fn derotate_index(&self, geometry: Geometry, index: u8) -> u8{
         match self {
             Rotation::Straight => Rotation::Straight,
             Rotation::Right => Rotation::Left,
             Rotation::Double => Rotation::Double,
             Rotation::Left => Rotation::Right
-        }.rotate_index(index)
+        }.rotate_index(geometry, index)
     }
 
-    pub fn rotate_matrix<T: Copy>(&self, matrix: [T; N_MOVES]) -> [T; N_MOVES] {
+    pub fn rotate_matrix<T: Copy>(&self, geometry: Geometry, matrix: [T; MAX_CELLS]) -> [T; MAX_CELLS] {
         let mut matrix2 = matrix;
-        for i in 0..N_MOVES {
-            matrix2[self.rotate_index(i as u8) as usize] = matrix[i as usize]
+        for i in 0..geometry.n_cells() {
+            matrix2[self.rotate_index(geometry, i as u8) as usize] = matrix[i]
         }
-        return matrix2
+        matrix2
     }
 
     /*
     Not used currently
-    pub fn derotate_matrix<T: Copy>(&self, matrix: [T; N_MOVES]) -> [T; N_MOVES] {
+    pub fn derotate_matrix<T: Copy>(&self, geometry: Geometry, matrix: [T; MAX_CELLS]) -> [T; MAX_CELLS] {
         let mut matrix2 = matrix;
-        for i in 0..N_MOVES {
-            matrix2[self.derotate_index(i as u8) as usize] = matrix[i as usize]
+        for i in 0..geometry.n_cells() {
+            matrix2[self.derotate_index(geometry, i as u8) as usize] = matrix[i as usize]
         }
         return matrix2
     }
     */
 }
 
+impl Symmetry {
+    pub const IDENTITY: Symmetry = Symmetry { rotation: Rotation::Straight, reflected: false };
+
+    pub const ALL: [Symmetry; 8] = [
+        Symmetry { rotation: Rotation::Straight, reflected: false },
+        Symmetry { rotation: Rotation::Right, reflected: false },
+        Symmetry { rotation: Rotation::Double, reflected: false },
+        Symmetry { rotation: Rotation::Left, reflected: false },
+        Symmetry { rotation: Rotation::Straight, reflected: true },
+        Symmetry { rotation: Rotation::Right, reflected: true },
+        Symmetry { rotation: Rotation::Double, reflected: true },
+        Symmetry { rotation: Rotation::Left, reflected: true },
+    ];
+
+    pub fn is_reflection(&self) -> bool {
+        self.reflected
+    }
+
+    pub fn fits(&self, geometry: Geometry) -> bool {
+        self.rotation.fits(geometry)
+    }
+
+    /// The symmetry that does `other` and then `self`.
+    pub fn compose(&self, other: Symmetry) -> Symmetry {
+        // mirroring turns a clockwise rotation into an anticlockwise one, so a mirror on
+        // the left can be moved past `other`'s rotation by negating it
+        let (mine, theirs) = (self.rotation.quarter_turns(), other.rotation.quarter_turns());
+        let turns = if self.reflected { mine + 4 - theirs } else { mine + theirs };
+        Symmetry { rotation: Rotation::from_quarter_turns(turns), reflected: self.reflected != other.reflected }
+    }
+
+    pub fn inverse(&self) -> Symmetry {
+        if self.reflected {
+            // every mirror-then-rotate is a mirror along some axis, so it undoes itself
+            *self
+        } else {
+            Symmetry { rotation: Rotation::from_quarter_turns(4 - self.rotation.quarter_turns()), reflected: false }
+        }
+    }
+
+    pub fn transform_index(&self, geometry: Geometry, index: u8) -> u8 {
+        assert!((index as usize) < geometry.n_cells());
+        let index = if self.reflected { 
+            let w = geometry.width() as u8;
+            let (x, y) = (index % w, index / w);
+            y * w + (w - 1 - x)
+        } else { 
+            index 
+        };
+        self.rotation.rotate_index(geometry, index)
+    }
+
+    pub fn transform_matrix<T: Copy>(&self, geometry: Geometry, matrix: [T; MAX_CELLS]) -> [T; MAX_CELLS] {
+        let mut matrix2 = matrix;
+        for i in 0..geometry.n_cells() {
+            matrix2[self.transform_index(geometry, i as u8) as usize] = matrix[i]
+        }
+        matrix2
+    }
+
+    /// The symmetry of `geometry` that gives the smallest key. Ties go to whichever comes first in `ALL`.
+    pub fn canonical_by_key<K: Ord>(geometry: Geometry, key: impl Fn(Symmetry) -> K) -> Symmetry {
+        geometry.layout().symmetries.iter().copied().min_by_key(|s| key(*s)).unwrap()
+    }
+}
+
+impl From<Rotation> for Symmetry {
+    fn from(rotation: Rotation) -> Symmetry {
+        Symmetry { rotation, reflected: false }
+    }
+}
+
+#[cfg(test)]
+fn pad(cells: &[u8]) -> [u8; MAX_CELLS] {
+    let mut out = [0; MAX_CELLS];
+    out[..cells.len()].copy_from_slice(cells);
+    out
+}
+
+#[test]
+fn test_symmetry_group() {
+    let square = Geometry::new(4, 4, 3).unwrap();
+    let rect = Geometry::new(4, 3, 3).unwrap();
+    for geometry in [Geometry::STANDARD, square, rect] {
+        let group = &geometry.layout().symmetries;
+        for a in group.iter() {
+            assert_eq!(a.compose(a.inverse()), Symmetry::IDENTITY);
+            assert_eq!(a.inverse().compose(*a), Symmetry::IDENTITY);
+            for b in group.iter() {
+                assert!(group.contains(&a.compose(*b)));
+                for i in 0..geometry.n_cells() as u8 {
+                    assert_eq!(a.compose(*b).transform_index(geometry, i), a.transform_index(geometry, b.transform_index(geometry, i)));
+                }
+            }
+        }
+
+        // all different
+        for (i, a) in group.iter().enumerate() {
+            for b in group[i + 1..].iter() {
+                assert!((0..geometry.n_cells() as u8).any(|i| a.transform_index(geometry, i) != b.transform_index(geometry, i)));
+            }
+        }
+    }
+
+    // the matrix version agrees with rotate_matrix, and with the tables this used to have
+    let geometry = Geometry::STANDARD;
+    let cells = pad(&[0, 1, 2, 3, 4, 5, 6, 7, 8]);
+    for r in Rotation::ALL {
+        assert_eq!(Symmetry::from(r).transform_matrix(geometry, cells), r.rotate_matrix(geometry, cells));
+    }
+    let right: Vec<u8> = (0..9).map(|i| Rotation::Right.rotate_index(geometry, i)).collect();
+    assert_eq!(right, vec![6, 3, 0, 7, 4, 1, 8, 5, 2]);
+    let left: Vec<u8> = (0..9).map(|i| Rotation::Left.rotate_index(geometry, i)).collect();
+    assert_eq!(left, vec![2, 5, 8, 1, 4, 7, 0, 3, 6]);
+}
+
+#[test]
+fn test_canonical() {
+    // every orientation of a position has the same canonical form
+    let geometry = Geometry::STANDARD;
+    let cells = pad(&[1, 0, 0, 2, 1, 0, 0, 0, 2]);
+    let canonical = |cells: [u8; MAX_CELLS]| {
+        Symmetry::canonical_by_key(geometry, |s| s.transform_matrix(geometry, cells)).transform_matrix(geometry, cells)
+    };
+    for s in Symmetry::ALL {
+        assert_eq!(canonical(s.transform_matrix(geometry, cells)), canonical(cells));
+    }
+}
+
 #[test]
 fn test_inverses() {
-    for i in 0..N_MOVES as u8 {
-        assert!(i == Rotation::Straight.rotate_index(i));
-        assert!(i == Rotation::Straight.rotate_index(Rotation::Straight.rotate_index(i)));
-        assert!(i == Rotation::Right.rotate_index(Rotation::Left.rotate_index(i)));
-        assert!(i == Rotation::Double.rotate_index(Rotation::Double.rotate_index(i)));
-        assert!(i == Rotation::Left.rotate_index(Rotation::Right.rotate_index(i)));
-
-        assert!(i == Rotation::Straight.derotate_index(Rotation::Straight.rotate_index(i)));
-        assert!(i == Rotation::Left.derotate_index(Rotation::Left.rotate_index(i)));
-        assert!(i == Rotation::Double.derotate_index(Rotation::Double.rotate_index(i)));
-        assert!(i == Rotation::Right.derotate_index(Rotation::Right.rotate_index(i)));
-
-        assert!(i == Rotation::Straight.derotate_index(Rotation::Straight.derotate_index(i)));
-        assert!(i == Rotation::Left.derotate_index(Rotation::Right.derotate_index(i)));
-        assert!(i == Rotation::Double.derotate_index(Rotation::Double.derotate_index(i)));
-        assert!(i == Rotation::Right.derotate_index(Rotation::Left.derotate_index(i)));
-    }
-}
\ No newline at end of file
+    let g = Geometry::STANDARD;
+    for i in 0..g.n_cells() as u8 {
+        assert!(i == Rotation::Straight.rotate_index(g, i));
+        assert!(i == Rotation::Straight.rotate_index(g, Rotation::Straight.rotate_index(g, i)));
+        assert!(i == Rotation::Right.rotate_index(g, Rotation::Left.rotate_index(g, i)));
+        assert!(i == Rotation::Double.rotate_index(g, Rotation::Double.rotate_index(g, i)));
+        assert!(i == Rotation::Left.rotate_index(g, Rotation::Right.rotate_index(g, i)));
+
+        assert!(i == Rotation::Straight.derotate_index(g, Rotation::Straight.rotate_index(g, i)));
+        assert!(i == Rotation::Left.derotate_index(g, Rotation::Left.rotate_index(g, i)));
+        assert!(i == Rotation::Double.derotate_index(g, Rotation::Double.rotate_index(g, i)));
+        assert!(i == Rotation::Right.derotate_index(g, Rotation::Right.rotate_index(g, i)));
+
+        assert!(i == Rotation::Straight.derotate_index(g, Rotation::Straight.derotate_index(g, i)));
+        assert!(i == Rotation::Left.derotate_index(g, Rotation::Right.derotate_index(g, i)));
+        assert!(i == Rotation::Double.derotate_index(g, Rotation::Double.derotate_index(g, i)));
+        assert!(i == Rotation::Right.derotate_index(g, Rotation::Left.derotate_index(g, i)));
+    }
+}
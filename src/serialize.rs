@@ -0,0 +1,134 @@
+//! Serde representations for the types that can't just derive one.
+//!
+//! A `Board` is stored as the game that led to it (geometry, wincons, front end rotation and
+//! moves) and replayed on the way back in, so a saved board can't be an impossible position.
+//! `InfosetKey`s are decimal strings in human-readable formats, since JavaScript can't hold a u128.
+
+use std::convert::TryFrom;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+
+use crate::{game::{Board, InfosetKey, Move, Outcome}, geometry::Geometry, rotation::Rotation};
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct BoardRepr {
+    geometry: Geometry,
+    rotation: Rotation,
+    p0_wants: Outcome,
+    p1_wants: Outcome,
+    moves: Vec<Move>,
+}
+
+impl From<Board> for BoardRepr {
+    fn from(board: Board) -> BoardRepr {
+        BoardRepr { 
+            geometry: board.geometry(), 
+            rotation: board.rotation, 
+            p0_wants: board.p0_wants, 
+            p1_wants: board.p1_wants, 
+            moves: board.moves(),
+        }
+    }
+}
+
+impl TryFrom<BoardRepr> for Board {
+    type Error = String;
+
+    fn try_from(repr: BoardRepr) -> Result<Board, String> {
+        if !repr.rotation.fits(repr.geometry) {
+            return Err(format!("a {:?} rotation doesn't fit a board that isn't square", repr.rotation))
+        }
+        let mut board = Board::with_geometry(repr.geometry, repr.p0_wants, repr.p1_wants);
+        for m in repr.moves {
+            board.try_play(m).map_err(|e| e.to_string())?;
+        }
+        board.rotation = repr.rotation;
+        Ok(board)
+    }
+}
+
+// the same fields as `Geometry` itself serializes, so formats that aren't self-describing can read it back
+#[derive(Deserialize)]
+pub(crate) struct GeometryRepr {
+    width: u8,
+    height: u8,
+    line_length: u8,
+}
+
+impl TryFrom<GeometryRepr> for Geometry {
+    type Error = String;
+
+    fn try_from(repr: GeometryRepr) -> Result<Geometry, String> {
+        Geometry::new(repr.width as usize, repr.height as usize, repr.line_length as usize).ok_or_else(|| format!(
+            "can't play on a {}x{} board with lines of {}", repr.width, repr.height, repr.line_length
+        ))
+    }
+}
+
+impl Serialize for InfosetKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.raw().to_string())
+        } else {
+            serializer.serialize_u128(self.raw())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for InfosetKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<InfosetKey, D::Error> {
+        let raw = if deserializer.is_human_readable() {
+            String::deserialize(deserializer)?.parse().map_err(de::Error::custom)?
+        } else {
+            u128::deserialize(deserializer)?
+        };
+        Ok(InfosetKey::from_raw(raw))
+    }
+}
+
+#[test]
+fn test_board_json() {
+    let mut board = Board::with_geometry(Geometry::new(4, 3, 3).unwrap(), Outcome::P0Win, Outcome::Tie);
+    for m in [5, 0, 6] { board.play(Move(m)) }
+    board.rotation = Rotation::Double;
+
+    let json = serde_json::to_string(&board).unwrap();
+    assert_eq!(
+        json, 
+        r#"{"geometry":{"width":4,"height":3,"line_length":3},"rotation":"Double","p0_wants":"P0Win","p1_wants":"Tie","moves":[5,0,6]}"#
+    );
+    let back: Board = serde_json::from_str(&json).unwrap();
+    assert_eq!(back.to_string(), board.to_string());
+    assert_eq!(back.moves(), board.moves());
+
+    // the moves are replayed, so they have to be legal
+    let bad = json.replace("[5,0,6]", "[5,5]");
+    assert!(serde_json::from_str::<Board>(&bad).err().unwrap().to_string().contains("cell 5 is already taken"));
+    let bad = json.replace(r#""width":4"#, r#""width":40"#);
+    assert!(serde_json::from_str::<Board>(&bad).is_err());
+    // and the rotation has to keep the board the same shape
+    let bad = json.replace(r#""rotation":"Double""#, r#""rotation":"Right""#);
+    assert!(serde_json::from_str::<Board>(&bad).err().unwrap().to_string().contains("doesn't fit"));
+}
+
+#[test]
+fn test_board_bincode() {
+    let mut board = Board::with_geometry(Geometry::new(5, 4, 4).unwrap(), Outcome::P1Win, Outcome::P0Win);
+    for m in [6, 19, 0] { board.play(Move(m)) }
+
+    let data = bincode::serialize(&(board.clone(), board.infoset_key(crate::game::CellValue::P0))).unwrap();
+    let (back, key): (Board, (InfosetKey, u32)) = bincode::deserialize(&data).unwrap();
+    assert_eq!(back.to_string(), board.to_string());
+    assert_eq!(key, board.infoset_key(crate::game::CellValue::P0));
+}
+
+#[test]
+fn test_key_json() {
+    let key = InfosetKey::from_raw(u128::MAX);
+    let json = serde_json::to_string(&key).unwrap();
+    assert_eq!(json, format!("\"{}\"", u128::MAX));
+    assert_eq!(serde_json::from_str::<InfosetKey>(&json).unwrap(), key);
+
+    assert_eq!(serde_json::to_string(&crate::game::MoveSet::from_mask(0b101)).unwrap(), "5");
+    assert_eq!(serde_json::to_string(&[Outcome::Tie, Outcome::P1Win]).unwrap(), r#"["Tie","P1Win"]"#);
+}
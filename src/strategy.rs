@@ -1,102 +1,333 @@
-use std::{collections::HashMap};
+use std::{borrow::Cow, cell::RefCell, cmp::Ordering, fmt};
 
-use crate::{consts::N_MOVES, game::{Move, Board}};
+use crate::{consts::{MAX_CELLS, N_WINCONS}, game::{Move, MoveSet, Board, InfosetKey, canonical_history}, geometry::Geometry, policy::uniform_over};
 
-const STRATEGY_DATA: &[u8; 321492] = include_bytes!("strategy.dat");
-const VAL_EXPANSION: [f32; 16] = [0.0, 0.01, 0.1, 0.2, 0.3, 0.33333, 0.4, 0.5, 0.6, 0.666666, 0.7, 0.8, 0.9, 0.98, 0.99, 1.0];
+pub mod codec;
 
+pub use codec::StrategyHeader;
+
+#[cfg(feature = "embedded-strategy")]
+const STRATEGY_DATA: &[u8] = include_bytes!("strategy.dat");
+
+// how many entries there are between checkpoints in a strategy's index
+const INDEX_STRIDE: usize = 32;
+
+/// A loaded strategy file.
+///
+/// Entries stay encoded: we keep a sparse index of every `INDEX_STRIDE`th entry, and a lookup
+/// scans forward from the nearest checkpoint, only decoding the moves of the entry it wants.
 pub struct Strategy {
-    items: HashMap<(u32, u32), [f32; N_MOVES]>
+    header: StrategyHeader,
+    geometry: Geometry,
+    data: Cow<'static, [u8]>,
+    index: Vec<Checkpoint>,
+}
+
+struct Checkpoint {
+    key: u128,  // history << 2 | private
+    offset: u32,
+    last_tag: u128,  // the tag of the entry before, which this entry's tag is a delta from
 }
 
+/// Why a strategy file couldn't be decoded. Offsets are byte offsets into the file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StrategyError {
+    /// The file ends partway through an entry's tag.
+    TruncatedTag { offset: usize },
+    /// A tag doesn't come after the tag before it, so the file isn't sorted.
+    OutOfOrder { offset: usize },
+    /// A move nibble names a cell that isn't on the board.
+    BadIndex { offset: usize, index: u16 },
+    /// An entry gives every move zero probability.
+    EmptyDistribution { offset: usize },
+    /// Two entries have the same (history, private) key.
+    DuplicateKey { offset: usize, history: u128, private: u32 },
+    /// The file ends with bytes that don't make up a complete entry.
+    TrailingBytes { offset: usize },
+    /// The file is too short to hold a header.
+    TruncatedHeader,
+    /// The file doesn't start with the magic number, so it's probably a legacy file or not a strategy at all.
+    BadMagic,
+    UnsupportedVersion { version: u8 },
+    UnknownFlags { flags: u8 },
+    /// The file was trained on a board we can't play, or one too big for the format.
+    IncompatibleBoard { width: u8, height: u8, line_length: u8 },
+    /// The file was trained on a game with a different number of wincons.
+    IncompatibleRules { n_wincons: u8 },
+    /// The header's probability table doesn't start at 0, or has values that aren't between 0 and 1.
+    BadValExpansion,
+    /// The encoder was given a key too big to fit in a tag, or one that isn't a game it can canonicalise.
+    KeyOutOfRange { history: u128, private: u32 },
+}
+
+impl fmt::Display for StrategyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StrategyError::TruncatedTag { offset } => write!(f, "strategy data ends in the middle of a tag (at byte {})", offset),
+            StrategyError::OutOfOrder { offset } => write!(f, "tag at byte {} is out of order", offset),
+            StrategyError::BadIndex { offset, index } => write!(f, "move index {} at byte {} is off the board", index, offset),
+            StrategyError::EmptyDistribution { offset } => write!(f, "entry at byte {} has no nonzero probabilities", offset),
+            StrategyError::DuplicateKey { offset, history, private } => 
+                write!(f, "entry at byte {} repeats key (history {}, private {})", offset, history, private),
+            StrategyError::TrailingBytes { offset } => write!(f, "incomplete entry at byte {}", offset),
+            StrategyError::TruncatedHeader => write!(f, "strategy data is too short to have a header"),
+            StrategyError::BadMagic => write!(f, "strategy data doesn't start with a strategy header"),
+            StrategyError::UnsupportedVersion { version } => write!(f, "unsupported strategy format version {}", version),
+            StrategyError::UnknownFlags { flags } => write!(f, "unknown strategy flags {:#04x}", flags),
+            StrategyError::IncompatibleBoard { width, height, line_length } => write!(
+                f, "strategy is for a {}x{} board with lines of {}, which isn't a board we can play",
+                width, height, line_length
+            ),
+            StrategyError::IncompatibleRules { n_wincons } => 
+                write!(f, "strategy is for a game with {} wincons, not {}", n_wincons, N_WINCONS),
+            StrategyError::BadValExpansion => write!(f, "strategy's probability table has values that aren't probabilities"),
+            StrategyError::KeyOutOfRange { history, private } => 
+                write!(f, "key (history {}, private {}) doesn't fit the strategy format", history, private),
+        }
+    }
+}
+
+impl std::error::Error for StrategyError {}
+
 thread_local! {
-    pub static STRATEGY: Strategy = Strategy::load();
+    // the strategy used for advice. starts out as the embedded one, if there is one
+    static STRATEGY: RefCell<Strategy> = RefCell::new(Strategy::embedded());
+}
+
+pub(crate) fn with_strategy<T>(f: impl FnOnce(&Strategy) -> T) -> T {
+    STRATEGY.with(|s| f(&s.borrow()))
+}
+
+pub(crate) fn install_strategy(strategy: Strategy) {
+    STRATEGY.with(|s| *s.borrow_mut() = strategy)
+}
+
+// the entries are a lot of bytes, so just say how many there are
+impl fmt::Debug for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Strategy")
+            .field("header", &self.header)
+            .field("geometry", &self.geometry)
+            .field("bytes", &self.data.len())
+            .finish_non_exhaustive()
+    }
 }
 
 impl Strategy {
-    fn load() -> Self {
-        let mut last_tag: u32 = 0;
-        let mut i = 0;
-
-        let mut all_strategies = HashMap::new();
-
-        loop {
-            // try to read tag
-            if i >= STRATEGY_DATA.len() { break; }
-
-            let byte1 = STRATEGY_DATA[i];
-
-            let (tag, i2) = 
-                if byte1 & 0b11000000 == 0 {
-                    // that was the whole tag, and it was a delta
-                    (last_tag + STRATEGY_DATA[i] as u32, i + 1)
-                } else if byte1 & 0b11000000 == 0b01000000 {
-                    (
-                        last_tag + u16::from_be_bytes([STRATEGY_DATA[i] & 0b00111111, STRATEGY_DATA[i + 1]]) as u32,
-                        i + 2
-                    )
-                } else {
-                    assert!(byte1 & 0b10000000 == 0b10000000);
-                    (
-                        u32::from_be_bytes([
-                            STRATEGY_DATA[i] & 0b01111111,
-                            STRATEGY_DATA[i + 1],
-                            STRATEGY_DATA[i + 2],
-                            STRATEGY_DATA[i + 3],
-                        ]), 
-                        i + 4
-                    )
-                };
-            i = i2;
-            last_tag = tag;
+    /// A strategy with no entries: every position falls back to uniform play.
+    pub fn empty() -> Self {
+        Strategy::index(StrategyHeader::current(Geometry::STANDARD, 0, 0, 0.0, 0.0), Cow::Borrowed(&[]), 0)
+            .expect("an empty strategy is valid")
+    }
+
+    /// The strategy built into the crate, or an empty one without the `embedded-strategy` feature.
+    #[cfg(feature = "embedded-strategy")]
+    pub fn embedded() -> Self {
+        // predates the header
+        Strategy::index(StrategyHeader::legacy(), Cow::Borrowed(STRATEGY_DATA), 0).expect("embedded strategy should be valid")
+    }
 
-            let n_nonzero = tag & 0b1111;
-            let private = (tag >> 4) & 0b11;
-            let history = tag >> 6;
-
-            let mut strategy = [0.0; N_MOVES];
-            for _ in 0..n_nonzero {
-                let ix_val = STRATEGY_DATA[i as usize];
-                let ix = ix_val >> 4;
-                let val = ix_val & 0b00001111;
-                strategy[ix as usize] = VAL_EXPANSION[val as usize];
-                assert!((0..N_MOVES).contains(&(ix as usize)));
-                i += 1;
+    #[cfg(not(feature = "embedded-strategy"))]
+    pub fn embedded() -> Self {
+        Strategy::empty()
+    }
+
+    /// Load a strategy in the format written by the trainer.
+    /// Fails if the file's header says it was trained for a game we can't play.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, StrategyError> {
+        let (header, body_start) = StrategyHeader::read(data)?;
+        header.check_compatible()?;
+        Strategy::index(header, Cow::Owned(data.to_vec()), body_start)
+    }
+
+    /// Load a strategy from before files had headers. These were all trained on the standard game.
+    pub fn from_legacy_bytes(data: &[u8]) -> Result<Self, StrategyError> {
+        Strategy::index(StrategyHeader::legacy(), Cow::Owned(data.to_vec()), 0)
+    }
+
+    // validate the entries and build the index
+    fn index(header: StrategyHeader, data: Cow<'static, [u8]>, body_start: usize) -> Result<Self, StrategyError> {
+        let mut index = vec![];
+        let mut n_entries = 0;
+        let mut last_tag = 0;
+        let geometry = header.geometry().expect("compatible headers have a valid board");
+        codec::decode_entries(&data, body_start, &header, |offset, tag, _| {
+            if n_entries % INDEX_STRIDE == 0 {
+                index.push(Checkpoint { key: tag >> header.count_bits(), offset: offset as u32, last_tag })
             }
+            n_entries += 1;
+            last_tag = tag;
+            Ok(())
+        })?;
 
-            all_strategies.insert((history, private), strategy);
-        }
+        Ok(Strategy { header, geometry, data, index })
+    }
+
+    pub fn header(&self) -> &StrategyHeader {
+        &self.header
+    }
 
-        return Strategy { items: all_strategies }
+    /// The board this strategy was trained on.
+    pub fn geometry(&self) -> Geometry {
+        self.geometry
     }
 
-    pub fn distribution(&self, board: &Board) -> [f32; 9] {
+    /// Boards of other geometries get uniform play, since nothing in the table applies to them.
+    pub fn distribution(&self, board: &Board) -> [f32; MAX_CELLS] {
+        if board.geometry() != self.geometry {
+            return uniform_over(board.possible_moves())
+        }
+
         let player = board.turn % 2;
         let (history, private) = board.infoset.to_key(player==0);
-        return self.key_distribution(history, private, &board.possible_moves());
+        if !self.header.canonical_keys() {
+            return self.key_distribution(history, private, board.possible_moves())
+        }
+
+        // look the position up in its canonical orientation, then turn the answer back around
+        let (symmetry, canonical) = canonical_history(self.geometry, history);
+        let canonical_moves: MoveSet = board.possible_moves().iter()
+            .map(|m| Move(symmetry.transform_index(self.geometry, m.0 as u8) as usize))
+            .collect();
+        let dist = self.key_distribution(canonical, private, canonical_moves);
+        symmetry.inverse().transform_matrix(self.geometry, dist)
     }
 
-    pub(crate) fn key_distribution(&self, history: u32, private: u32, possible_moves_if_defaulting: &[Move]) -> [f32; 9] {
-        let strategy = self.items.get(&(history, private));
+    pub(crate) fn key_distribution(&self, history: InfosetKey, private: u32, possible_moves_if_defaulting: MoveSet) -> [f32; MAX_CELLS] {
+        let strategy = entry_key(history, private).and_then(|key| self.lookup(key));
          if let Some(s) = strategy {
-            let s = *s;
+            // the header's table has no negative values and the decoder rejects all-zero entries, so sum is positive
             let sum: f32 = s.iter().sum();
-            assert!(sum > 0.0);
             s.map(|x| x/sum)
         } else {
-            let mut s2 = [0.0; N_MOVES];
-            let n_possible_moves = possible_moves_if_defaulting.len();
-            for i in possible_moves_if_defaulting {
-                s2[i.0 as usize] = 1.0/(n_possible_moves as f32);
+            uniform_over(possible_moves_if_defaulting)
+        }
+    }
+
+    // the (unnormalised) strategy stored for an entry key, if there is one
+    fn lookup(&self, key: u128) -> Option<[f32; MAX_CELLS]> {
+        let count_bits = self.header.count_bits();
+
+        // the last checkpoint at or before the key, and where the next one starts
+        let ix = self.index.partition_point(|c| c.key <= key);
+        let checkpoint = self.index.get(ix.checked_sub(1)?)?;
+        let end = self.index.get(ix).map(|c| c.offset as usize).unwrap_or(self.data.len());
+
+        // everything was validated when we built the index, so decoding can't fail from here
+        let mut i = checkpoint.offset as usize;
+        let mut last_tag = checkpoint.last_tag;
+        while i < end {
+            let (tag, moves_start) = codec::read_tag(&self.header, &self.data, i, last_tag).expect("strategy was validated");
+            let n_nonzero = (tag & ((1 << count_bits) - 1)) as u32;
+            match (tag >> count_bits).cmp(&key) {
+                Ordering::Less => {
+                    i = moves_start + n_nonzero as usize * self.header.move_bytes();
+                    last_tag = tag;
+                }
+                Ordering::Equal => {
+                    return Some(
+                        codec::read_moves(&self.data, moves_start, n_nonzero, &self.header)
+                            .expect("strategy was validated")
+                    )
+                }
+                Ordering::Greater => return None
             }
-            s2
         }
+        None
     }
 }
 
+// the key entries are sorted by, if the history is small enough to have one
+fn entry_key(history: InfosetKey, private: u32) -> Option<u128> {
+    if history.raw() >> 126 != 0 { return None }
+    Some(history.raw() << 2 | private as u128)
+}
+
+#[cfg(feature = "embedded-strategy")]
 #[test]
 fn test_load_strategy() {
-    let strategy = Strategy::load();
-    println!("{:?}", strategy.key_distribution(0, 0, &[Move(0), Move(1), Move(2)]));
-    assert!(1 == 1); // make sure we didn't fuckin crash
-}
\ No newline at end of file
+    let strategy = Strategy::embedded();
+    let dist = strategy.key_distribution(InfosetKey::EMPTY, 0, MoveSet::from_mask(0b111));
+    println!("{:?}", dist);
+    assert!((dist.iter().sum::<f32>() - 1.0).abs() < 1e-5); // make sure we didn't fuckin crash
+
+    // the index has to find exactly what a full decode does
+    let (_, entries) = codec::decode_legacy(STRATEGY_DATA).unwrap();
+    for ((history, private), expected) in entries.iter() {
+        assert_eq!(strategy.lookup(entry_key(*history, *private).unwrap()), Some(*expected));
+        assert_eq!(
+            strategy.lookup(entry_key(*history, *private + 1).unwrap()).is_some(), 
+            entries.binary_search_by_key(&(*history, *private + 1), |e| e.0).is_ok()
+        );
+    }
+    assert_eq!(strategy.lookup(0), None);
+    assert_eq!(strategy.lookup(u128::MAX), None);
+    assert_eq!(entry_key(InfosetKey::from_raw(u128::MAX), 0), None);
+}
+
+#[test]
+fn test_bad_strategies() {
+    // long-form tag, but only two of its four bytes are there
+    assert_eq!(Strategy::from_legacy_bytes(&[0b10000000, 0]).err(), Some(StrategyError::TruncatedTag { offset: 0 }));
+    // tag promises one move, but there isn't one
+    assert_eq!(Strategy::from_legacy_bytes(&[0b00000001]).err(), Some(StrategyError::TrailingBytes { offset: 0 }));
+    // cell 9 doesn't exist
+    assert_eq!(Strategy::from_legacy_bytes(&[0b00000001, 0x9f]).err(), Some(StrategyError::BadIndex { offset: 1, index: 9 }));
+    // the only move has probability 0
+    assert_eq!(Strategy::from_legacy_bytes(&[0b00000001, 0x10]).err(), Some(StrategyError::EmptyDistribution { offset: 0 }));
+    // second entry is a zero delta from the first
+    assert_eq!(
+        Strategy::from_legacy_bytes(&[0b00000001, 0x1f, 0b00000000, 0x1f]).err(),
+        Some(StrategyError::DuplicateKey { offset: 2, history: 0, private: 0 })
+    );
+    // full-form tag that goes backwards
+    assert_eq!(
+        Strategy::from_legacy_bytes(&[0b01000001, 0x01, 0x1f, 0b10000000, 0, 0, 1, 0x1f]).err(),
+        Some(StrategyError::OutOfOrder { offset: 3 })
+    );
+}
+
+#[test]
+fn test_canonical_keys() {
+    use crate::{game::Outcome, rotation::Symmetry, strategy::codec::TrainedInfoset};
+
+    // p0 opened in the corner, p1 took the edge to its right; p0 always takes the far corner
+    let g = Geometry::STANDARD;
+    let mut strategy = [0.0; MAX_CELLS];
+    strategy[8] = 1.0;
+    let mut legal = [false; MAX_CELLS];
+    legal[2..9].fill(true);
+    let pack = |moves: &[usize]| InfosetKey::from_moves(g, &moves.iter().map(|m| Move(*m)).collect::<Vec<_>>()).unwrap();
+    let history = pack(&[0, 1]);
+
+    let data = codec::encode(
+        &StrategyHeader::current(g, 0, 0, 0.0, 0.0), 
+        vec![((history, 1), TrainedInfoset { legal, strategy })]
+    ).unwrap();
+    let strategy = Strategy::from_bytes(&data).unwrap();
+    assert!(strategy.header().canonical_keys());
+
+    // the same position, mirrored: p1 took the edge below instead
+    for (p1_move, far_corner) in [(1, 8), (3, 8)] {
+        let mut board = Board::new(Outcome::P0Win, Outcome::Tie);
+        board.play(Move(0));
+        board.play(Move(p1_move));
+        assert_eq!(strategy.distribution(&board)[far_corner], 1.0);
+    }
+
+    // a position that isn't in the table still gets uniform play
+    let mut board = Board::new(Outcome::P0Win, Outcome::Tie);
+    board.play(Move(0));
+    board.play(Move(4));
+    assert!(strategy.distribution(&board).iter().all(|x| *x == 0.0 || (*x - 1.0 / 7.0).abs() < 1e-6));
+
+    // every orientation of a history has the same canonical form
+    for s in Symmetry::ALL {
+        let moved = [0, 5, 7].map(|m| s.transform_index(g, m as u8) as usize);
+        assert_eq!(canonical_history(g, pack(&moved)).1, canonical_history(g, pack(&[0, 5, 7])).1);
+    }
+
+    // boards of another size fall back to uniform
+    let board = Board::with_geometry(Geometry::new(4, 4, 3).unwrap(), Outcome::P0Win, Outcome::Tie);
+    assert_eq!(strategy.distribution(&board), uniform_over(board.possible_moves()));
+}
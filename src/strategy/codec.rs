@@ -0,0 +1,576 @@
+//! The strategy file format.
+//!
+//! A file is a `StrategyHeader` followed by one entry per interesting infoset, sorted by key.
+//! Each entry is a tag packing `history << 2 | private` above a move count, then that many moves,
+//! each packing `move << 4 | probability`, where the probability is a nibble indexing `val_expansion`.
+//!
+//! Since version 3, tags are `key << 5 | n_nonzero`, written as LEB128 deltas from the previous tag,
+//! so histories can be as wide as an `InfosetKey`. Moves take one byte on boards of up to 16 cells,
+//! and two (big-endian) on bigger ones.
+//!
+//! Before that, tags were `key << 4 | n_nonzero`, written as a delta from the previous tag when it fit
+//! in 6 or 14 bits and as a full 31-bit tag otherwise, and moves always took one byte.
+//!
+//! Since version 2, files can set `FLAG_CANONICAL_KEYS`: then each history is stored in the orientation
+//! picked by `game::canonical_history`, and its strategy is rotated/mirrored to match.
+
+use std::{collections::HashMap, convert::TryInto};
+
+use crate::{consts::{MAX_CELLS, N_WINCONS}, game::{InfosetKey, canonical_history}, geometry::Geometry};
+
+use super::StrategyError;
+
+/// The default quantisation table.
+pub const VAL_EXPANSION: [f32; 16] = [0.0, 0.01, 0.1, 0.2, 0.3, 0.33333, 0.4, 0.5, 0.6, 0.666666, 0.7, 0.8, 0.9, 0.98, 0.99, 1.0];
+
+const MAGIC: [u8; 4] = *b"TT2S";
+const FORMAT_VERSION: u8 = 3;
+
+/// Histories are stored in their canonical orientation under the board's symmetries. (Version 2+)
+pub const FLAG_CANONICAL_KEYS: u8 = 0b00000001;
+const HEADER_LEN: usize = 98;
+
+/// Metadata at the start of a strategy file, describing the game it was trained on and how.
+///
+/// Layout (big-endian): magic `TT2S`, version, flags, width, height, line length, wincon count,
+/// iterations (u64), contempt iterations (u64), contempt start (f32), contempt floor (f32),
+/// then the 16 f32s that the probability nibbles expand to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StrategyHeader {
+    pub version: u8,
+    pub flags: u8,
+    pub width: u8,
+    pub height: u8,
+    pub line_length: u8,
+    pub n_wincons: u8,
+    pub iterations: u64,
+    /// Contempt falls linearly from `contempt_start` to 0 over this many iterations...
+    pub contempt_iterations: u64,
+    pub contempt_start: f32,
+    /// ... but never drops below this.
+    pub contempt_floor: f32,
+    pub val_expansion: [f32; 16],
+}
+
+impl StrategyHeader {
+    /// What we assume about files from before the header existed.
+    /// The contempt schedule is what the trainer used at the time; the iteration count wasn't recorded.
+    pub fn legacy() -> Self {
+        StrategyHeader {
+            version: 0,
+            flags: 0,
+            width: Geometry::STANDARD.width() as u8,
+            height: Geometry::STANDARD.height() as u8,
+            line_length: Geometry::STANDARD.line_length() as u8,
+            n_wincons: N_WINCONS as u8,
+            iterations: 0,
+            contempt_iterations: 10000,
+            contempt_start: 0.5,
+            contempt_floor: 0.01,
+            val_expansion: VAL_EXPANSION,
+        }
+    }
+
+    /// A header for a file in the current format, for a game on the given board.
+    pub fn current(geometry: Geometry, iterations: u64, contempt_iterations: u64, contempt_start: f32, contempt_floor: f32) -> Self {
+        StrategyHeader {
+            version: FORMAT_VERSION,
+            flags: FLAG_CANONICAL_KEYS,
+            width: geometry.width() as u8,
+            height: geometry.height() as u8,
+            line_length: geometry.line_length() as u8,
+            iterations, contempt_iterations, contempt_start, contempt_floor,
+            ..StrategyHeader::legacy()
+        }
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend(MAGIC);
+        out.extend([self.version, self.flags, self.width, self.height, self.line_length, self.n_wincons]);
+        out.extend(self.iterations.to_be_bytes());
+        out.extend(self.contempt_iterations.to_be_bytes());
+        out.extend(self.contempt_start.to_be_bytes());
+        out.extend(self.contempt_floor.to_be_bytes());
+        for v in self.val_expansion {
+            out.extend(v.to_be_bytes());
+        }
+    }
+
+    /// Read a header, returning it with the offset where the entries start.
+    /// This only checks that the header is well-formed: see `check_compatible`.
+    pub fn read(data: &[u8]) -> Result<(Self, usize), StrategyError> {
+        if data.len() < HEADER_LEN { 
+            return Err(if data.starts_with(&MAGIC) { StrategyError::TruncatedHeader } else { StrategyError::BadMagic })
+        }
+        if data[0..4] != MAGIC { return Err(StrategyError::BadMagic) }
+
+        let version = data[4];
+        if !(1..=FORMAT_VERSION).contains(&version) { return Err(StrategyError::UnsupportedVersion { version }) }
+
+        let u64_at = |i: usize| u64::from_be_bytes(data[i..i + 8].try_into().unwrap());
+        let f32_at = |i: usize| f32::from_be_bytes(data[i..i + 4].try_into().unwrap());
+
+        let mut val_expansion = [0.0; 16];
+        for (i, v) in val_expansion.iter_mut().enumerate() {
+            *v = f32_at(34 + i * 4);
+        }
+
+        let header = StrategyHeader {
+            version,
+            flags: data[5],
+            width: data[6],
+            height: data[7],
+            line_length: data[8],
+            n_wincons: data[9],
+            iterations: u64_at(10),
+            contempt_iterations: u64_at(18),
+            contempt_start: f32_at(26),
+            contempt_floor: f32_at(30),
+            val_expansion,
+        };
+        Ok((header, HEADER_LEN))
+    }
+
+    /// The board the strategy was trained on, if it's one we can play.
+    pub fn geometry(&self) -> Option<Geometry> {
+        Geometry::new(self.width as usize, self.height as usize, self.line_length as usize)
+    }
+
+    pub fn canonical_keys(&self) -> bool {
+        self.flags & FLAG_CANONICAL_KEYS != 0
+    }
+
+    /// How many low bits of a tag hold the entry's move count.
+    pub(crate) fn count_bits(&self) -> u32 {
+        if self.version >= 3 { 5 } else { 4 }
+    }
+
+    /// How many bytes each of an entry's moves takes.
+    pub(crate) fn move_bytes(&self) -> usize {
+        if self.width as usize * self.height as usize <= 16 { 1 } else { 2 }
+    }
+
+    /// Whether a strategy with this header can be used for a game this crate plays.
+    pub fn check_compatible(&self) -> Result<(), StrategyError> {
+        let known_flags = if self.version >= 2 { FLAG_CANONICAL_KEYS } else { 0 };
+        if self.flags & !known_flags != 0 { return Err(StrategyError::UnknownFlags { flags: self.flags }) }
+        // before version 3, moves and move counts were nibbles, so a strategy could only cover 16 cells
+        let max_cells = if self.version >= 3 { MAX_CELLS } else { 16 };
+        if self.geometry().is_none_or(|g| g.n_cells() > max_cells) {
+            return Err(StrategyError::IncompatibleBoard { width: self.width, height: self.height, line_length: self.line_length })
+        }
+        if self.n_wincons as usize != N_WINCONS {
+            return Err(StrategyError::IncompatibleRules { n_wincons: self.n_wincons })
+        }
+        StrategyHeader::check_val_expansion(&self.val_expansion)
+    }
+
+    /// Whether `table` can expand a file's probability nibbles. Nibble 0 is how an entry says "never",
+    /// and the others have to be probabilities, or advice comes out NaN.
+    pub fn check_val_expansion(table: &[f32; 16]) -> Result<(), StrategyError> {
+        if table[0] != 0.0 || table.iter().any(|v| !(0.0..=1.0).contains(v)) || table.iter().all(|v| *v == 0.0) {
+            return Err(StrategyError::BadValExpansion)
+        }
+        Ok(())
+    }
+}
+
+/// The trainer's final word on one infoset: which moves were legal, and its average strategy.
+#[derive(Clone, Copy, Debug)]
+pub struct TrainedInfoset {
+    pub legal: [bool; MAX_CELLS],
+    pub strategy: [f32; MAX_CELLS],
+}
+
+/// A (history, private) key and what the trainer learned there.
+pub type TrainedEntry = ((InfosetKey, u32), TrainedInfoset);
+
+/// Write a strategy file. Infosets whose strategy is close to uniform are left out, since that's
+/// what the reader falls back to anyway, and the rest are quantised to `header.val_expansion`.
+///
+/// Keys are the raw histories from a game on the header's board. If the header asks for canonical keys, symmetric
+/// histories are merged by averaging their (reoriented) strategies.
+///
+/// Files are always written in the current format, whatever `header.version` says.
+pub fn encode(header: &StrategyHeader, infosets: impl IntoIterator<Item = TrainedEntry>) -> Result<Vec<u8>, StrategyError> {
+    let header = &StrategyHeader { version: FORMAT_VERSION, ..header.clone() };
+    header.check_compatible()?;
+
+    let mut out: Vec<u8> = Vec::new();
+    header.write(&mut out);
+
+    let geometry = header.geometry().expect("compatible headers have a valid board");
+    let mut sorted_infosets: Vec<_> = if header.canonical_keys() {
+        canonicalise(geometry, infosets)?
+    } else {
+        infosets.into_iter().collect()
+    };
+    sorted_infosets.sort_by_key(|(key, _)| *key);
+
+    let mut last_tag: u128 = 0;
+
+    for ((history, private), node) in sorted_infosets.iter() {
+        let (history, private) = (*history, *private);
+        if !is_interesting(node) { continue; }
+
+        // cells past the end of the board are never legal
+        let simp = &simplify(&header.val_expansion, node.strategy)[..geometry.n_cells()];
+        let n_nonzero = simp.iter().filter(|x| **x != 0).count() as u32;
+
+        // everything rounded to zero: may as well fall back to uniform
+        if n_nonzero == 0 { continue; }
+
+        // real games always fit, but keys from elsewhere might not
+        if history.raw() >> (126 - header.count_bits()) != 0 || private & 0b11 != private {
+            return Err(StrategyError::KeyOutOfRange { history: history.raw(), private })
+        }
+
+        let tag = (history.raw() << 2 | private as u128) << header.count_bits() | n_nonzero as u128;
+        write_varint(&mut out, tag - last_tag);
+        last_tag = tag;
+
+        for (i, value) in simp.iter().enumerate() {
+            if *value == 0 { continue }
+            let ix_val = (i as u16) << 4 | *value as u16;
+            if header.move_bytes() == 1 { out.push(ix_val as u8) } else { out.extend(ix_val.to_be_bytes()) }
+        }
+    }
+
+    Ok(out)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut x: u128) {
+    while x >= 0x80 {
+        out.push(x as u8 | 0x80);
+        x >>= 7;
+    }
+    out.push(x as u8);
+}
+
+fn canonicalise(geometry: Geometry, infosets: impl IntoIterator<Item = TrainedEntry>) -> Result<Vec<TrainedEntry>, StrategyError> {
+    let mut merged: HashMap<(InfosetKey, u32), (TrainedInfoset, u32)> = HashMap::new();
+    for ((history, private), node) in infosets {
+        // only histories of real games can be turned around
+        if InfosetKey::from_moves(geometry, &history.moves(geometry)) != Some(history) {
+            return Err(StrategyError::KeyOutOfRange { history: history.raw(), private })
+        }
+        let (symmetry, canonical) = canonical_history(geometry, history);
+        let legal = symmetry.transform_matrix(geometry, node.legal);
+        let strategy = symmetry.transform_matrix(geometry, node.strategy);
+
+        let (sum, count) = merged.entry((canonical, private))
+            .or_insert((TrainedInfoset { legal: [false; MAX_CELLS], strategy: [0.0; MAX_CELLS] }, 0));
+        for i in 0..geometry.n_cells() {
+            sum.legal[i] |= legal[i];
+            sum.strategy[i] += strategy[i];
+        }
+        *count += 1;
+    }
+
+    Ok(merged.into_iter().map(|(key, (mut node, count))| {
+        for x in node.strategy.iter_mut() { *x /= count as f32 }
+        (key, node)
+    }).collect())
+}
+
+/// A decoded (history, private) key and its strategy.
+pub type DecodedEntry = ((InfosetKey, u32), [f32; MAX_CELLS]);
+
+/// Read a whole strategy file into its header and a table of (unnormalised) strategies, sorted by key.
+pub fn decode(data: &[u8]) -> Result<(StrategyHeader, Vec<DecodedEntry>), StrategyError> {
+    let (header, body_start) = StrategyHeader::read(data)?;
+    header.check_compatible()?;
+    let entries = decode_body(&header, data, body_start)?;
+    Ok((header, entries))
+}
+
+/// Like `decode`, but for files from before the header existed.
+pub fn decode_legacy(data: &[u8]) -> Result<(StrategyHeader, Vec<DecodedEntry>), StrategyError> {
+    let header = StrategyHeader::legacy();
+    let entries = decode_body(&header, data, 0)?;
+    Ok((header, entries))
+}
+
+fn decode_body(header: &StrategyHeader, data: &[u8], body_start: usize) -> Result<Vec<DecodedEntry>, StrategyError> {
+    let mut entries = vec![];
+    decode_entries(data, body_start, header, |_, tag, strategy| {
+        let key = tag >> header.count_bits();
+        entries.push(((InfosetKey::from_raw(key >> 2), (key & 0b11) as u32), strategy));
+        Ok(())
+    })?;
+    Ok(entries)
+}
+
+// whether the distribution is substantially different from picking uniformly at random
+fn is_interesting(node: &TrainedInfoset) -> bool {
+    let n_legal = node.legal.iter().filter(|l| **l).count();
+    if n_legal == 0 { return false }
+
+    // calculate bhattacharyya distance
+    let mut bhat = 0.0;
+    for (legal, p) in node.legal.iter().zip(node.strategy) {
+        if *legal {
+            bhat += (p / n_legal as f32).sqrt();
+        }
+    }
+    bhat < 0.9
+}
+
+fn simplify(possibilities: &[f32; 16], strategy: [f32; MAX_CELLS]) -> [u8; MAX_CELLS] {
+    let simplify_term = |term: f32| {
+        let dist = possibilities.map(|p| {
+            ((term - p).abs() * 100000.0) as u32
+        });
+
+        (0..possibilities.len()).min_by_key(|i| dist[*i]).unwrap() as u8
+    };
+
+    strategy.map(simplify_term)
+}
+
+// Walk the entries of a strategy file starting at `start`, calling `f` with (offset, tag, strategy) for each.
+// This is where all the validation happens: everything that gets through is sorted and well-formed.
+pub(crate) fn decode_entries(
+    data: &[u8], 
+    start: usize,
+    header: &StrategyHeader,
+    mut f: impl FnMut(usize, u128, [f32; MAX_CELLS]) -> Result<(), StrategyError>
+) -> Result<(), StrategyError> {
+    let count_bits = header.count_bits();
+    let mut last_tag: Option<u128> = None;
+    let mut i = start;
+
+    while i < data.len() {
+        let entry_start = i;
+        let (tag, moves_start) = read_tag(header, data, i, last_tag.unwrap_or(0))?;
+
+        let n_nonzero = (tag & ((1 << count_bits) - 1)) as u32;
+        let private = ((tag >> count_bits) & 0b11) as u32;
+        let history = tag >> (count_bits + 2);
+
+        if let Some(last_tag) = last_tag {
+            // the move count is in the low bits, so equal keys can still have increasing tags
+            if last_tag >> count_bits == tag >> count_bits { 
+                return Err(StrategyError::DuplicateKey { offset: entry_start, history, private })
+            }
+            if last_tag > tag { return Err(StrategyError::OutOfOrder { offset: entry_start }) }
+        }
+        last_tag = Some(tag);
+
+        i = moves_start + n_nonzero as usize * header.move_bytes();
+        if i > data.len() {
+            return Err(StrategyError::TrailingBytes { offset: entry_start })
+        }
+
+        let strategy = read_moves(data, moves_start, n_nonzero, header)?;
+        if strategy.iter().all(|x| *x == 0.0) {
+            return Err(StrategyError::EmptyDistribution { offset: entry_start })
+        }
+
+        f(entry_start, tag, strategy)?;
+    }
+
+    Ok(())
+}
+
+// Read the tag of the entry at `i`, given the tag of the entry before it (0 if there isn't one).
+// Returns the tag and the offset of the entry's moves.
+pub(crate) fn read_tag(header: &StrategyHeader, data: &[u8], i: usize, last_tag: u128) -> Result<(u128, usize), StrategyError> {
+    let tag_byte = |j: usize| data.get(j).copied().ok_or(StrategyError::TruncatedTag { offset: i });
+
+    if header.version >= 3 {
+        let mut delta: u128 = 0;
+        let mut j = i;
+        for shift in (0..128).step_by(7) {
+            let byte = tag_byte(j)?;
+            j += 1;
+            let part = (byte & 0b01111111) as u128;
+            // a delta that doesn't fit can't lead anywhere sorted
+            if part > u128::MAX >> shift { return Err(StrategyError::OutOfOrder { offset: i }) }
+            delta |= part << shift;
+            if byte & 0b10000000 == 0 {
+                let tag = last_tag.checked_add(delta).ok_or(StrategyError::OutOfOrder { offset: i })?;
+                return Ok((tag, j))
+            }
+        }
+        return Err(StrategyError::OutOfOrder { offset: i })
+    }
+
+    let last_tag = last_tag as u32;
+    let byte1 = tag_byte(i)?;
+
+    // tags are either a 6-bit delta, a 14-bit delta, or a full 31-bit tag
+    let (tag, moves_start) = 
+        if byte1 & 0b11000000 == 0 {
+            (last_tag.checked_add(byte1 as u32), i + 1)
+        } else if byte1 & 0b11000000 == 0b01000000 {
+            let delta = u16::from_be_bytes([byte1 & 0b00111111, tag_byte(i + 1)?]);
+            (last_tag.checked_add(delta as u32), i + 2)
+        } else {
+            (
+                Some(u32::from_be_bytes([
+                    byte1 & 0b01111111,
+                    tag_byte(i + 1)?,
+                    tag_byte(i + 2)?,
+                    tag_byte(i + 3)?,
+                ])), 
+                i + 4
+            )
+        };
+    let tag = tag.ok_or(StrategyError::OutOfOrder { offset: i })?;
+    Ok((tag as u128, moves_start))
+}
+
+// Read `n_nonzero` move bytes starting at `i`. The caller makes sure they're all there.
+pub(crate) fn read_moves(data: &[u8], i: usize, n_nonzero: u32, header: &StrategyHeader) -> Result<[f32; MAX_CELLS], StrategyError> {
+    let n_cells = header.width as usize * header.height as usize;
+    let move_bytes = header.move_bytes();
+    let mut strategy = [0.0; MAX_CELLS];
+    for (j, bytes) in data[i..i + n_nonzero as usize * move_bytes].chunks(move_bytes).enumerate() {
+        let ix_val = bytes.iter().fold(0, |acc, b| acc << 8 | *b as u16);
+        let ix = ix_val >> 4;
+        let val = ix_val & 0b00001111;
+        if ix as usize >= n_cells { return Err(StrategyError::BadIndex { offset: i + j * move_bytes, index: ix }) }
+        strategy[ix as usize] = header.val_expansion[val as usize];
+    }
+    Ok(strategy)
+}
+
+#[test]
+fn test_strategy_header() {
+    let header = StrategyHeader::current(Geometry::STANDARD, 40000, 10000, 0.5, 0.01);
+    let mut data = vec![];
+    header.write(&mut data);
+    assert_eq!(data.len(), HEADER_LEN);
+    data.extend([0b00000001, 0x1f]);
+
+    let (decoded_header, entries) = decode(&data).unwrap();
+    assert_eq!(decoded_header, header);
+    let mut expected = [0.0; MAX_CELLS];
+    expected[1] = 1.0;
+    assert_eq!(entries, vec![((InfosetKey::from_raw(0), 0), expected)]);
+
+    // the same entry in the old format, where a one-byte tag delta has a 4-bit move count
+    let old_header = StrategyHeader { version: 2, ..header.clone() };
+    let mut old_data = vec![];
+    old_header.write(&mut old_data);
+    old_data.extend([0b00010001, 0x1f]);
+    assert_eq!(decode(&old_data).unwrap().1, vec![((InfosetKey::from_raw(0), 1), expected)]);
+
+    // legacy data has no header
+    assert_eq!(decode(&data[HEADER_LEN..]).err(), Some(StrategyError::BadMagic));
+    assert_eq!(decode(&data[..20]).err(), Some(StrategyError::TruncatedHeader));
+
+    // other boards are fine. past 16 cells, moves take two bytes
+    let biggest_board = StrategyHeader::current(Geometry::new(5, 5, 4).unwrap(), 40000, 10000, 0.5, 0.01);
+    let mut data = vec![];
+    biggest_board.write(&mut data);
+    data.extend([0b00000001, 0x01, 0x8f]);
+    let mut expected = [0.0; MAX_CELLS];
+    expected[24] = 1.0;
+    assert_eq!(decode(&data).unwrap(), (biggest_board, vec![((InfosetKey::from_raw(0), 0), expected)]));
+
+    let bigger_board = StrategyHeader::current(Geometry::new(4, 4, 3).unwrap(), 40000, 10000, 0.5, 0.01);
+    let mut data = vec![];
+    bigger_board.write(&mut data);
+    data.extend([0b00000001, 0xff]);
+    assert_eq!(decode(&data).unwrap().0, bigger_board);
+    let mut data = vec![];
+    header.write(&mut data);
+    data.extend([0b00000001, 0xff]);
+    assert_eq!(decode(&data).err(), Some(StrategyError::BadIndex { offset: HEADER_LEN + 1, index: 15 }));
+
+    for (version, width, height, line_length) in [(3, 6, 5, 3), (3, 3, 3, 4), (3, 0, 3, 3), (2, 5, 5, 3)] {
+        let mut wrong_board = header.clone();
+        wrong_board.version = version;
+        wrong_board.width = width;
+        wrong_board.height = height;
+        wrong_board.line_length = line_length;
+        let mut data = vec![];
+        wrong_board.write(&mut data);
+        assert_eq!(
+            decode(&data).err(), 
+            Some(StrategyError::IncompatibleBoard { width, height, line_length })
+        );
+    }
+
+    // keys that can't have come from a game might not fit in a tag
+    let mut legal = [false; MAX_CELLS];
+    legal[..2].fill(true);
+    let mut strategy = [0.0; MAX_CELLS];
+    strategy[0] = 1.0;
+    assert_eq!(
+        encode(&header, vec![((InfosetKey::from_raw(u128::MAX), 0), TrainedInfoset { legal, strategy })]).err(),
+        Some(StrategyError::KeyOutOfRange { history: u128::MAX, private: 0 })
+    );
+
+    // the probability table has to expand nibbles to probabilities, with 0 for "never"
+    let mut tables = [VAL_EXPANSION; 5];
+    tables[0] = [0.0; 16];
+    tables[1][3] = -0.2;
+    tables[2][3] = f32::NAN;
+    tables[3][3] = 1.5;
+    tables[4][0] = 0.1;
+    for table in tables {
+        let wrong_table = StrategyHeader { val_expansion: table, ..header.clone() };
+        let mut data = vec![];
+        wrong_table.write(&mut data);
+        data.extend([0b00000001, 0x1f]);
+        assert_eq!(decode(&data).err(), Some(StrategyError::BadValExpansion));
+    }
+
+    let mut wrong_rules = header;
+    wrong_rules.n_wincons = 2;
+    let mut data = vec![];
+    wrong_rules.write(&mut data);
+    assert_eq!(decode(&data).err(), Some(StrategyError::IncompatibleRules { n_wincons: 2 }));
+}
+
+#[test]
+fn test_round_trip() {
+    use std::collections::HashMap;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    let mut rng = StdRng::seed_from_u64(0);
+    // random histories aren't real games, so skip canonicalisation
+    for _ in 0..100 {
+        let geometry = [
+            Geometry::STANDARD, Geometry::new(4, 4, 3).unwrap(), Geometry::new(2, 5, 2).unwrap(), Geometry::new(5, 5, 4).unwrap()
+        ][rng.gen_range(0..4)];
+        let mut header = StrategyHeader::current(geometry, 1234, 10000, 0.5, 0.01);
+        header.flags = 0;
+
+        // random table with a mix of near-uniform and lopsided strategies
+        let mut table = HashMap::new();
+        for _ in 0..rng.gen_range(0..200) {
+            // keys from long games on big boards take most of a u128
+            let bits = rng.gen_range(1..121);
+            let history = InfosetKey::from_raw(rng.gen_range(1..1 << bits));
+            let private = rng.gen_range(0..N_WINCONS as u32);
+
+            let mut legal = [false; MAX_CELLS];
+            let mut strategy = [0.0; MAX_CELLS];
+            let peakiness = rng.gen_range(0.0..8.0f32);
+            for i in 0..geometry.n_cells() {
+                legal[i] = rng.gen_bool(0.7);
+                if legal[i] { strategy[i] = rng.gen_range(0.0..1.0f32).powf(peakiness) }
+            }
+            let sum: f32 = strategy.iter().sum();
+            if sum == 0.0 { continue }
+            for x in strategy.iter_mut() { *x /= sum }
+
+            table.insert((history, private), TrainedInfoset { legal, strategy });
+        }
+
+        let data = encode(&header, table.clone()).unwrap();
+        let (decoded_header, entries) = decode(&data).unwrap();
+        assert_eq!(decoded_header, header);
+
+        let mut expected: Vec<_> = table.iter()
+            .filter(|(_, node)| is_interesting(node))
+            .map(|(key, node)| (*key, simplify(&header.val_expansion, node.strategy).map(|v| header.val_expansion[v as usize])))
+            .collect();
+        expected.sort_by_key(|(key, _)| *key);
+        assert_eq!(entries, expected);
+    }
+}
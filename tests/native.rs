@@ -0,0 +1,44 @@
+//! The engine, used the way a native Rust consumer would: through the crate's public API only.
+
+use tictac2player::{
+    policy::{Minimax, TakeWins, Uniform, uniform_over}, 
+    Board, CellValue, GameRecord, Geometry, IllegalMove, Move, MoveSet, Outcome, Policy, Strategy,
+};
+
+#[test]
+fn play_a_game() {
+    let strategy = Strategy::embedded();
+    let mut board = Board::new(Outcome::P0Win, Outcome::Tie);
+    assert_eq!(board.geometry(), Geometry::STANDARD);
+
+    while board.score().is_none() {
+        let policy: &dyn Policy = if board.next_to_move() == CellValue::P0 { &strategy } else { &Minimax };
+        let dist = TakeWins(policy).distribution(&board);
+        assert!((dist.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+
+        let best = board.possible_moves().iter()
+            .max_by(|a, b| dist[a.index()].partial_cmp(&dist[b.index()]).unwrap())
+            .unwrap();
+        board.try_play(best).unwrap();
+    }
+    assert_eq!(board.try_play(Move::new(0)), Err(IllegalMove::GameOver));
+
+    let record: GameRecord = board.record().to_string().parse().unwrap();
+    assert_eq!(record.moves(), board.moves().as_slice());
+    assert_eq!(board.turn(), board.moves().len());
+
+    // both show up in test failures, so they had better print
+    assert!(format!("{:?}", board).starts_with("Board { geometry: Geometry { width: 3, height: 3, line_length: 3 }"));
+    assert!(format!("{:?}", strategy).starts_with("Strategy { header: StrategyHeader {"));
+}
+
+#[test]
+fn other_geometries() {
+    let geometry = Geometry::new(4, 4, 3).unwrap();
+    let mut board = Board::with_geometry(geometry, Outcome::Tie, Outcome::P1Win);
+    board.play(Move::new(5));
+    assert_eq!(board.cell(5), CellValue::P0);
+    assert_eq!(board.possible_moves().len(), 15);
+    assert_eq!(Uniform.distribution(&board), uniform_over(board.possible_moves()));
+    assert_eq!(board.winning_moves(CellValue::P1), MoveSet::EMPTY);
+}
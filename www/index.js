@@ -105,6 +105,23 @@ import("./rsmodel.js").then(rsmodel => {
         }
     }
 
+    // !!! SAVE: the round in progress and the score survive a reload
+    let saveKey = "batLikesKobold";
+    let saveGame = function() {
+        try {
+            localStorage.setItem(saveKey, JSON.stringify({ round: rsmodel.save(), players: players }));
+        } catch (e) {
+            console.warn("couldn't save game:", e);
+        }
+    }
+    let loadGame = function() {
+        let saved = null;
+        try { saved = JSON.parse(localStorage.getItem(saveKey)); } catch (e) {}
+        if (saved == null || !rsmodel.restore(saved.round)) { return false; }
+        players = saved.players;
+        return true;
+    }
+
     // !!! TUTORIAL: set up event handlers
     gotoButtons.forEach((btn) => {
         btn.onclick = () => {
@@ -238,9 +255,16 @@ import("./rsmodel.js").then(rsmodel => {
         } else {
             nextGameRow.classList.remove("inactive");
         }
+
+        saveGame();
     };
 
-    rsmodel.start();
+    if (loadGame()) {
+        activeState = "game";
+        findWork();
+    } else {
+        rsmodel.start();
+    }
     bounceView();
 });
 
@@ -19,7 +19,12 @@ let viewBoard = function() {
     let outcome = view.outcome;
     let advice = [];
     let cells = [];
-    for (var i = 0; i < 9; i++) {
+    let beliefs = [[], []];
+    for (var w = 0; w < 3; w++) {
+        beliefs[0].push(view.get_belief_p0(w));
+        beliefs[1].push(view.get_belief_p1(w));
+    }
+    for (var i = 0; i < view.width * view.height; i++) {
         advice.push(view.get_advice(i));
 
         let value = view.get_cell(i);
@@ -30,6 +35,8 @@ let viewBoard = function() {
         )
     }
     let view_data = {
+        width: view.width,
+        height: view.height,
         playerTurn: view.player_turn,
         outcome: outcome == 255 ? null : {
             winner: 
@@ -40,6 +47,8 @@ let viewBoard = function() {
             util: [view.util_p0, view.util_p1]
         },
         wants: [view.wants_p0, view.wants_p1],
+        // beliefs[p][w]: how likely player p is to want w, going by their moves (w coded like wants)
+        beliefs: beliefs,
         advice: advice,
         cells: cells,
         hinted: temporary.hinted,
@@ -59,7 +68,12 @@ let peek = function() {
 
 let play = function(cell, progressedCb) {
     let old_view = board.js_view();
-    board.js_play(cell)
+    try {
+        board.js_play(cell)
+    } catch (e) {
+        // e.g. clicking a taken cell. nothing happens, but say why
+        console.warn("illegal move:", e)
+    }
     let new_view = board.js_view();
     let progressed = old_view.player_turn != new_view.player_turn;
     if (progressed) {
@@ -68,10 +82,60 @@ let play = function(cell, progressedCb) {
     }
 }
 
+// take back the last move. progressedCb gets the new view, like with play
+let undo = function(progressedCb) {
+    if (board.js_undo()) {
+        temporary.hinted = false
+        progressedCb(viewBoard())
+    }
+}
+
+// the game so far, as text that the engine's GameRecord can replay
+let record = function() {
+    return board.js_serialize()
+}
+
+// everything about the current round, as a string for localStorage
+let save = function() {
+    return JSON.stringify({ board: board.js_serialize(), temporary: temporary })
+}
+
+// resume a round from save(). returns false (and leaves the current round alone) if it can't
+let restore = function(saved) {
+    let restored;
+    try {
+        let data = JSON.parse(saved);
+        restored = { board: wasm.Board.js_deserialize(data.board), temporary: data.temporary };
+    } catch (e) {
+        console.warn("couldn't restore game:", e)
+        return false
+    }
+    if (board != null) { board.free() }
+    board = restored.board
+    temporary = restored.temporary
+    return true
+}
+
+// bytes: a Uint8Array holding a strategy file, e.g. from fetch(...).arrayBuffer()
+let loadStrategy = function(bytes) {
+    wasm.load_strategy(bytes)
+}
+
+// name: "strategy", "uniform" or "minimax"
+let setAdvisor = function(name) {
+    wasm.set_advisor(name)
+}
+
 export {
+    loadStrategy,
+    setAdvisor,
     start,
     viewBoard,
     hint,
     peek,
     play,
+    undo,
+    record,
+    save,
+    restore,
 };
\ No newline at end of file